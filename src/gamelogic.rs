@@ -1,7 +1,13 @@
+use crate::map::Map;
 use crate::rendering::{Canvas, Position, Texture};
 use minifb::Key;
 use std::time::{Duration, SystemTime};
 
+const JUMP_SPEED: f32 = 2.5; // Upward speed when leaving the ground, world units per second
+const GRAVITY: f32 = 9.8;
+const CROUCH_DEPTH: f32 = 0.2; // How far crouching lowers the entity
+const CROUCH_SPEED: f32 = 5.0; // Fraction of a full crouch covered per second
+
 pub trait Moveable {
     fn get_position(&self) -> Position;
     fn get_angle(&self) -> f32;
@@ -9,21 +15,28 @@ pub trait Moveable {
     fn set_angle(&mut self, theta: f32);
     fn update_position(&mut self, x: f32, y: f32);
     fn update_angle(&mut self, theta: f32);
-    fn update_position_checked(&mut self, dx: f32, dy: f32, map: &Vec<Vec<usize>>) {
-        let Position { x, y } = self.get_position();
+    /// Looks up (positive) or down. Most things can't, so it does nothing by default
+    fn update_pitch(&mut self, _delta: f32) {}
+    /// Moves the entity up or down from where it normally stands, for jumping and crouching.
+    /// Does nothing by default
+    fn set_vertical_offset(&mut self, _offset: f32) {}
+    fn update_position_checked(&mut self, dx: f32, dy: f32, map: &Map) {
+        let from = self.get_position();
+        let Position { x, y } = from;
         let new_x = x + dx;
         let new_y = y + dy;
+        let blocked = |x, y| map.is_blocked(from, Position { x, y });
 
-        if map[new_y.floor() as usize][new_x.floor() as usize] == 0 {
+        if !blocked(new_x, new_y) {
             self.set_position(new_x, new_y);
             return;
         }
 
-        if map[new_y.floor() as usize][x.floor() as usize] == 0 {
+        if !blocked(x, new_y) {
             self.set_position(x, new_y);
         }
 
-        if map[y.floor() as usize][new_x.floor() as usize] == 0 {
+        if !blocked(new_x, y) {
             self.set_position(new_x, y);
         }
     }
@@ -36,6 +49,9 @@ pub struct UserMovementController<'a> {
     pub move_speed: f32,
     pub look_sense: f32,
     pub _marker: std::marker::PhantomData<&'a mut dyn Moveable>,
+    vertical_velocity: f32,
+    jump_height: f32, // Height above the ground while in the air
+    crouch: f32,      // 0.0 standing up to 1.0 fully crouched
 }
 
 impl<'a> UserMovementController<'a> {
@@ -45,12 +61,52 @@ impl<'a> UserMovementController<'a> {
             move_speed,
             look_sense,
             _marker: std::marker::PhantomData,
+            vertical_velocity: 0.0,
+            jump_height: 0.0,
+            crouch: 0.0,
+        }
+    }
+
+    /// Jumps on Space and crouches while Left Ctrl is held, call once per frame.
+    /// Jumping only works from the ground and not while crouched
+    pub fn vertical_input(&mut self, canvas: &Canvas, dt: Duration) {
+        self.update_vertical(
+            canvas.is_key_pressed(Key::Space),
+            canvas.is_key_down(Key::LeftCtrl),
+            dt,
+        );
+    }
+
+    /// The physics behind vertical_input, jump is only for the frame it was pressed on
+    /// while crouch is for as long as it's held
+    fn update_vertical(&mut self, jump: bool, crouch: bool, dt: Duration) {
+        let dt = dt.as_secs_f32();
+        let grounded = self.jump_height <= 0.0;
+
+        if grounded && self.crouch == 0.0 && jump {
+            self.vertical_velocity = JUMP_SPEED;
+        }
+
+        self.vertical_velocity -= GRAVITY * dt;
+        self.jump_height += self.vertical_velocity * dt;
+        if self.jump_height <= 0.0 {
+            self.jump_height = 0.0;
+            self.vertical_velocity = 0.0;
+        }
+
+        // Eases in and out of the crouch instead of snapping
+        let target = if crouch && grounded { 1.0 } else { 0.0 };
+        let step = CROUCH_SPEED * dt;
+        self.crouch += (target - self.crouch).clamp(-step, step);
+
+        unsafe {
+            (*self.entity).set_vertical_offset(self.jump_height - self.crouch * CROUCH_DEPTH);
         }
     }
 
     /// Reads movement inputs and enforces bounds checking
     /// For a supplied map
-    pub fn physics_input(&self, canvas: &Canvas, map: &Vec<Vec<usize>>) {
+    pub fn physics_input(&self, canvas: &Canvas, map: &Map) {
         if canvas.is_key_down(Key::Right) {
             unsafe {
                 (*self.entity).update_angle(self.look_sense);
@@ -59,7 +115,19 @@ impl<'a> UserMovementController<'a> {
 
         if canvas.is_key_down(Key::Left) {
             unsafe {
-                (*self.entity).update_angle(-1.0 * self.look_sense);
+                (*self.entity).update_angle(-self.look_sense);
+            }
+        }
+
+        if canvas.is_key_down(Key::Up) {
+            unsafe {
+                (*self.entity).update_pitch(self.look_sense);
+            }
+        }
+
+        if canvas.is_key_down(Key::Down) {
+            unsafe {
+                (*self.entity).update_pitch(-self.look_sense);
             }
         }
 
@@ -67,31 +135,47 @@ impl<'a> UserMovementController<'a> {
         let mut ny = 0.0;
 
         let angle = unsafe { (*self.entity).get_angle() }; // The lion does not use Rc<RefCell>
+        let move_speed = self.move_speed * (1.0 - self.crouch * 0.5); // Crouching walks at half speed
 
         if canvas.is_key_down(Key::W) {
-            nx += angle.cos() * self.move_speed;
-            ny += angle.sin() * self.move_speed;
+            nx += angle.cos() * move_speed;
+            ny += angle.sin() * move_speed;
         }
 
         if canvas.is_key_down(Key::S) {
-            nx += -1.0 * angle.cos() * self.move_speed;
-            ny += -1.0 * angle.sin() * self.move_speed;
+            nx += -angle.cos() * move_speed;
+            ny += -angle.sin() * move_speed;
         }
 
         if canvas.is_key_down(Key::A) {
-            nx += angle.sin() * self.move_speed;
-            ny += -1.0 * angle.cos() * self.move_speed;
+            nx += angle.sin() * move_speed;
+            ny += -angle.cos() * move_speed;
         }
 
         if canvas.is_key_down(Key::D) {
-            nx += -1.0 * angle.sin() * self.move_speed;
-            ny += angle.cos() * self.move_speed;
+            nx += -angle.sin() * move_speed;
+            ny += angle.cos() * move_speed;
         }
 
         unsafe {
             (*self.entity).update_position_checked(nx, ny, map);
         }
     }
+
+    /// Toggles the door directly in front of the entity when E is pressed
+    pub fn interact(&self, canvas: &Canvas, map: &mut Map) {
+        if !canvas.is_key_pressed(Key::E) {
+            return;
+        }
+
+        let (Position { x, y }, angle) =
+            unsafe { ((*self.entity).get_position(), (*self.entity).get_angle()) };
+        let (front_x, front_y) = (x + angle.cos(), y + angle.sin());
+
+        if front_x >= 0.0 && front_y >= 0.0 {
+            map.toggle_door(front_x as usize, front_y as usize);
+        }
+    }
 }
 
 pub struct Animation<'a> {
@@ -145,13 +229,12 @@ impl<'a> Animation<'a> {
 
                     self.last_frame_time = SystemTime::now();
                 }
-            },
+            }
             Err(_) => {
                 self.last_frame_time = SystemTime::now();
             }
         }
     }
-
 }
 
 #[derive(Debug, Clone)]
@@ -164,8 +247,8 @@ impl std::fmt::Display for AnimationError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         // Leaving space for other variants
         match self {
-            &AnimationError::NonExistentFrame => write!(f, "requested frame does not exist"),
-            &AnimationError::NonExistentAnimation => write!(f, "requested animation does not exist"),
+            AnimationError::NonExistentFrame => write!(f, "requested frame does not exist"),
+            AnimationError::NonExistentAnimation => write!(f, "requested animation does not exist"),
         }
     }
 }
@@ -186,4 +269,86 @@ impl<'a> AnimationManager<'a> {
             Ok(())
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: Duration = Duration::from_micros(16_667);
+    const EPSILON: f32 = 1e-4;
+
+    // Only remembers the vertical offset it was last given
+    struct Body {
+        offset: f32,
+    }
+
+    impl Moveable for Body {
+        fn get_position(&self) -> Position {
+            Position { x: 0.0, y: 0.0 }
+        }
+        fn get_angle(&self) -> f32 {
+            0.0
+        }
+        fn set_position(&mut self, _x: f32, _y: f32) {}
+        fn set_angle(&mut self, _theta: f32) {}
+        fn update_position(&mut self, _x: f32, _y: f32) {}
+        fn update_angle(&mut self, _theta: f32) {}
+        fn set_vertical_offset(&mut self, offset: f32) {
+            self.offset = offset;
+        }
+    }
+
+    #[test]
+    fn jump_rises_and_lands_back_on_the_ground() {
+        let mut body = Body { offset: 0.0 };
+        let mut controller = UserMovementController::new(&mut body, 0.0, 0.0);
+
+        controller.update_vertical(true, false, FRAME);
+        let mut peak: f32 = 0.0;
+        let mut frames = 1;
+        while controller.jump_height > 0.0 {
+            // Holding jump in the air does nothing
+            controller.update_vertical(true, false, FRAME);
+            peak = peak.max(controller.jump_height);
+            frames += 1;
+            assert!(frames < 120, "never landed");
+        }
+
+        // Peaks at v^2 / 2g and is up for 2v / g, give or take a frame of integration
+        let expected_peak = JUMP_SPEED * JUMP_SPEED / (2.0 * GRAVITY);
+        let expected_frames = 2.0 * JUMP_SPEED / GRAVITY / FRAME.as_secs_f32();
+        assert!((peak - expected_peak).abs() < 0.03);
+        assert!((frames as f32 - expected_frames).abs() <= 2.0);
+
+        assert_eq!(controller.jump_height, 0.0);
+        assert_eq!(controller.vertical_velocity, 0.0);
+        assert_eq!(body.offset, 0.0);
+    }
+
+    #[test]
+    fn crouch_eases_down_and_back_up() {
+        let mut body = Body { offset: 0.0 };
+        let mut controller = UserMovementController::new(&mut body, 0.0, 0.0);
+        let tenth = Duration::from_millis(100);
+
+        controller.update_vertical(false, true, tenth);
+        assert!((controller.crouch - CROUCH_SPEED * 0.1).abs() < EPSILON);
+        assert!((body.offset + controller.crouch * CROUCH_DEPTH).abs() < EPSILON);
+
+        controller.update_vertical(false, true, Duration::from_secs(1));
+        assert_eq!(controller.crouch, 1.0);
+        assert!((body.offset + CROUCH_DEPTH).abs() < EPSILON);
+
+        // Can't jump out of a crouch
+        controller.update_vertical(true, true, tenth);
+        assert_eq!(controller.jump_height, 0.0);
+
+        controller.update_vertical(false, false, tenth);
+        assert!((controller.crouch - (1.0 - CROUCH_SPEED * 0.1)).abs() < EPSILON);
+
+        controller.update_vertical(false, false, Duration::from_secs(1));
+        assert_eq!(controller.crouch, 0.0);
+        assert_eq!(body.offset, 0.0);
+    }
+}
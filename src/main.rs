@@ -1,29 +1,33 @@
 #![allow(dead_code)]
 
 mod gamelogic;
+mod map;
 mod rendering;
 
-use rendering::cameraspec::{CameraFog, CameraOptions, CameraOptionsBuilder};
-use rendering::{Camera, Skybox, Sprite, Texture};
+use minifb::Key;
+use rendering::cameraspec::{CameraFog, CameraOptions, CameraOptionsBuilder, SideShading, SkyFog};
+use rendering::lighting::{Flashlight, Lighting, PointLight};
+use rendering::raycast::WallSide;
+use rendering::{Camera, Position, Skybox, Sprite, Texture, Viewport};
 
 use gamelogic::{Moveable, UserMovementController};
+use map::{DoorAxis, Map, Tile};
+use std::time::Instant;
 
 const WINDOW_W: usize = 700;
 const WINDOW_H: usize = 700;
 const FPS: usize = 60;
-const FOCAL_DISTANCE: f32 = WINDOW_H as f32 / WINDOW_W as f32;
-const VIEWPORT_SIZE: f32 = 1.0; // Width of the viewport used for calculations
-const RAY_FINENESS: f32 = 200.0; // How much the dx and dy are divided by for each step in the raycast. Higher values lead to more accurate casts but slower performance
+const FOV: f32 = 60.0; // Horizontal field of view in degrees
 const PLAYER_VELOCITY: f32 = 0.04; // Scales the movement amount determined by the sin and cosine
 const LOOK_SENSE: f32 = 0.02; // Speed of rotation with arrow keys
 
 fn main() {
-    let map: Vec<Vec<usize>> = vec![
+    let grid: Vec<Vec<usize>> = vec![
         vec![1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1],
         vec![1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1],
-        vec![1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1],
-        vec![1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1],
-        vec![1, 1, 0, 2, 0, 0, 0, 2, 0, 0, 1, 1],
+        vec![1, 1, 0, 0, 0, 5, 3, 3, 3, 3, 1, 1],
+        vec![1, 1, 0, 0, 0, 5, 3, 3, 3, 3, 1, 1],
+        vec![1, 1, 0, 2, 0, 0, 0, 2, 4, 6, 1, 1],
         vec![1, 1, 0, 0, 0, 1, 1, 1, 0, 0, 1, 1],
         vec![1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1],
         vec![1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1],
@@ -40,39 +44,160 @@ fn main() {
         Texture::load_from_file("wall.jpg").unwrap_or(Texture::from_color(255));
     let brick_texture: Texture =
         Texture::load_from_file("brick_wall.jpg").unwrap_or(Texture::from_color(255));
-    let floor_color = from_u8_rgb(0, 0, 255);
+    let floor_texture = Texture::from_color(from_u8_rgb(90, 90, 100));
+    let ceiling_texture = Texture::from_color(from_u8_rgb(60, 50, 45));
 
-    let mut canvas = rendering::Canvas::new("badtracing", WINDOW_W, WINDOW_H).unwrap();
+    // Transparent everywhere but the bars
+    let grate_texture = Texture::from_image(image::DynamicImage::ImageRgba8(
+        image::RgbaImage::from_fn(64, 64, |x, y| {
+            if x % 16 < 3 || y % 16 < 3 {
+                image::Rgba([110, 110, 120, 255])
+            } else {
+                image::Rgba([0, 0, 0, 0])
+            }
+        }),
+    ));
+
+    // Magenta is keyed out, leaving a round lamp
+    let lamp_texture = Texture::from_image_keyed(
+        image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(32, 32, |x, y| {
+            let (dx, dy) = (x as f32 - 15.5, y as f32 - 15.5);
+            if dx * dx + dy * dy < 14.0 * 14.0 {
+                image::Rgb([255, 230, 180])
+            } else {
+                image::Rgb([255, 0, 255])
+            }
+        })),
+        from_u8_rgb(255, 0, 255),
+    );
+
+    // Tile ids used in the grid above. 0 is indoors, 3 is an open courtyard, 4 is a low wall
+    // 5 is a door between the two and 6 is a see-through grate
+    let mut map = Map::new(
+        grid,
+        vec![
+            Tile::empty()
+                .with_floor(&floor_texture)
+                .with_ceiling(&ceiling_texture),
+            Tile::wall(&tony_texture),
+            Tile::wall(&brick_texture).with_face(WallSide::South, &tony_texture),
+            Tile::empty().with_floor(&brick_texture).open_sky(),
+            Tile::wall(&brick_texture).with_height(0.4).open_sky(),
+            Tile::door(&tony_texture, DoorAxis::Y)
+                .with_floor(&floor_texture)
+                .with_ceiling(&ceiling_texture),
+            Tile::wall(&grate_texture)
+                .with_floor(&floor_texture)
+                .with_ceiling(&ceiling_texture),
+        ],
+    );
+
+    // Dim everywhere, a warm lamp in the first room and moonlight over the courtyard
+    let mut lighting = Lighting::new(0.15);
+    lighting.add_light(PointLight::new(
+        Position { x: 3.5, y: 3.0 },
+        4.0,
+        1.4,
+        from_u8_rgb(255, 200, 140),
+    ));
+    lighting.add_light(PointLight::new(
+        Position { x: 8.0, y: 3.0 },
+        5.0,
+        0.9,
+        from_u8_rgb(150, 170, 255),
+    ));
+
+    // `badtracing --headless out.png` renders a single frame to a png without opening a window
+    let args: Vec<String> = std::env::args().collect();
+    let screenshot_path = match args.get(1).map(|a| a.as_str()) {
+        Some("--headless") => Some(args.get(2).map_or("frame.png", |p| p.as_str())),
+        _ => None,
+    };
+
+    let mut canvas = match screenshot_path {
+        Some(_) => rendering::Canvas::headless(WINDOW_W, WINDOW_H),
+        None => rendering::Canvas::new("badtracing", WINDOW_W, WINDOW_H).unwrap(),
+    };
     let camera_options: CameraOptions = CameraOptionsBuilder::new()
-        .camera_fog(CameraFog::None)
-        .viewport_size(WINDOW_W as f32 / WINDOW_H as f32)
+        .camera_fog(CameraFog::ExponentialSquared {
+            density: 0.12,
+            fog_color: from_u8_rgb(20, 20, 24),
+        })
+        .sky_fog(SkyFog {
+            band: 0.4,
+            top_amount: 0.5,
+        })
+        .side_shading(SideShading::NorthSouth(30))
+        .flashlight(
+            Flashlight::new(0.6, 6.0, 1.2, from_u8_rgb(255, 245, 220)).with_drain_rate(0.01),
+        )
+        .fov_degrees(FOV)
         .into();
 
     let mut camera: Camera = camera_options.into();
 
     camera.set_position(4.0, 4.0);
+
+    // Picture in picture in the top right corner, watching the courtyard from its far end
+    let security_options: CameraOptions = CameraOptionsBuilder::new()
+        .position(Position { x: 9.6, y: 2.6 })
+        .view_angle(std::f32::consts::PI)
+        .pitch(-0.1)
+        .fov_degrees(80.0)
+        .viewport(Viewport::new(WINDOW_W - 210, 10, 200, 150))
+        .into();
+    let security_camera: Camera = security_options.into();
     canvas.set_target_fps(FPS);
 
     // I made the movement controller dereference a raw pointer
     // to a moveable trait object so watch yourself lmao
     // really just wanted to try it out, will probably shoot me in the foot later
-    let camera_controller =
+    let mut camera_controller =
         UserMovementController::new(&raw mut camera, PLAYER_VELOCITY, LOOK_SENSE);
     // Main loop
 
-    let mut test_sprite = Sprite::from_texture(&tony_texture);
+    // Tony from the front, bricks from behind
+    let mut test_sprite = Sprite::from_directions(vec![&tony_texture, &brick_texture]);
     test_sprite.set_position(4.2, 4.2);
     test_sprite.scale(0.5);
+    let mut lamp_sprite = Sprite::from_texture(&lamp_texture);
+    lamp_sprite.set_position(3.5, 3.0);
+    lamp_sprite.scale(0.3);
+    lamp_sprite.elevation(0.7); // Hangs from the ceiling
+    let mut last_frame = Instant::now();
     loop {
+        let now = Instant::now();
+        let dt = now - last_frame;
+        map.update_doors(dt, &[camera.get_position()]);
+        if let Some(flashlight) = camera.flashlight_mut() {
+            if canvas.is_key_pressed(Key::F) {
+                flashlight.toggle();
+            }
+            flashlight.update(dt);
+        }
+        test_sprite.update_angle(dt.as_secs_f32() * 0.5); // Slowly turns around
+        last_frame = now;
+
         // Using a canvas thing here, might want to make a gamecontext struct?
         // No real need for that yet
-        camera.draw_simple_floor(&mut canvas, floor_color);
-        camera.draw_skybox(&mut canvas, &skybox);
-        camera.main(&mut canvas, &map, &[&tony_texture, &brick_texture]);
-        camera.render_sprites(&mut canvas, &[&test_sprite]);
+        camera.draw_floor(&mut canvas, &map, Some(&skybox), &lighting);
+        camera.main(&mut canvas, &map, &lighting);
+        camera.render_sprites(&mut canvas, &map, &[&test_sprite, &lamp_sprite], &lighting);
+
+        security_camera.draw_floor(&mut canvas, &map, Some(&skybox), &lighting);
+        security_camera.main(&mut canvas, &map, &lighting);
+        security_camera.render_sprites(&mut canvas, &map, &[&test_sprite, &lamp_sprite], &lighting);
+
+        if let Some(path) = screenshot_path {
+            canvas.save_png(path).expect("failed to save frame");
+            return;
+        }
+
         canvas.update();
 
         camera_controller.physics_input(&canvas, &map);
+        camera_controller.vertical_input(&canvas, dt);
+        camera_controller.interact(&canvas, &mut map);
     }
 }
 
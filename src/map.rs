@@ -0,0 +1,399 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::rendering::raycast::WallSide;
+use crate::rendering::{Position, Texture};
+
+/// How far a door slides per second, a door takes one second to fully open
+const DOOR_SPEED: f32 = 1.0;
+
+/// How thick a door's slab is for collision, centered on the cell's midline
+const DOOR_THICKNESS: f32 = 0.1;
+
+/// Which way a door's slab runs through its cell. The slab sits on the cell's midline
+/// and slides along this axis when it opens
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DoorAxis {
+    X, // Slab runs along x, so it blocks movement in y
+    Y, // Slab runs along y, so it blocks movement in x
+}
+
+/// Per cell state of a door
+#[derive(Clone, Copy)]
+pub struct Door {
+    pub open: f32, // 0.0 is fully closed, 1.0 is fully open
+    pub opening: bool,
+    pub speed: f32,
+}
+
+impl Door {
+    fn new() -> Self {
+        Self {
+            open: 0.0,
+            opening: false,
+            speed: DOOR_SPEED,
+        }
+    }
+
+    /// A door that's closing waits while blocked, so it never closes on anything
+    fn update(&mut self, dt: Duration, blocked: bool) {
+        let step = self.speed * dt.as_secs_f32();
+        if self.opening {
+            self.open = (self.open + step).min(1.0);
+        } else if !blocked {
+            self.open = (self.open - step).max(0.0);
+        }
+    }
+}
+
+/// Texture for each face of a wall tile
+#[derive(Clone, Copy)]
+pub struct WallFaces<'a> {
+    pub north: &'a Texture,
+    pub south: &'a Texture,
+    pub east: &'a Texture,
+    pub west: &'a Texture,
+}
+
+impl<'a> WallFaces<'a> {
+    /// Same texture on every face
+    pub fn uniform(texture: &'a Texture) -> Self {
+        Self {
+            north: texture,
+            south: texture,
+            east: texture,
+            west: texture,
+        }
+    }
+
+    pub fn face(&self, side: WallSide) -> &'a Texture {
+        match side {
+            WallSide::North => self.north,
+            WallSide::South => self.south,
+            WallSide::East => self.east,
+            WallSide::West => self.west,
+        }
+    }
+
+    pub fn set_face(&mut self, side: WallSide, texture: &'a Texture) {
+        match side {
+            WallSide::North => self.north = texture,
+            WallSide::South => self.south = texture,
+            WallSide::East => self.east = texture,
+            WallSide::West => self.west = texture,
+        }
+    }
+}
+
+/// Describes what a tile id in the map grid looks like and how it behaves.
+/// Floors are only seen on tiles that aren't walls. A wall shorter than the ceiling
+/// shows its tile's ceiling (or the sky if it's open) above it, and nothing if it has neither
+#[derive(Clone, Copy)]
+pub struct Tile<'a> {
+    pub wall: Option<WallFaces<'a>>, // None means the tile can be walked through
+    pub floor: Option<&'a Texture>,
+    pub ceiling: Option<&'a Texture>,
+    pub open_sky: bool, // Skybox shows above this tile instead of a ceiling
+    pub height: f32,    // Height of the wall in world units, 1.0 is a normal wall
+    pub door: Option<DoorAxis>, // The wall texture is drawn on a sliding slab instead
+    pub top: Option<&'a Texture>, // Top of a wall that can be seen over, None uses the wall texture
+}
+
+impl<'a> Tile<'a> {
+    pub fn empty() -> Self {
+        Self {
+            wall: None,
+            floor: None,
+            ceiling: None,
+            open_sky: false,
+            height: 1.0,
+            door: None,
+            top: None,
+        }
+    }
+
+    pub fn wall(texture: &'a Texture) -> Self {
+        Self::wall_faces(WallFaces::uniform(texture))
+    }
+
+    pub fn wall_faces(faces: WallFaces<'a>) -> Self {
+        Self {
+            wall: Some(faces),
+            ..Self::empty()
+        }
+    }
+
+    pub fn door(texture: &'a Texture, axis: DoorAxis) -> Self {
+        Self {
+            wall: Some(WallFaces::uniform(texture)),
+            door: Some(axis),
+            ..Self::empty()
+        }
+    }
+
+    /// Swaps the texture on one face, does nothing to tiles without walls
+    pub fn with_face(mut self, side: WallSide, texture: &'a Texture) -> Self {
+        if let Some(faces) = &mut self.wall {
+            faces.set_face(side, texture);
+        }
+        self
+    }
+
+    pub fn with_floor(mut self, texture: &'a Texture) -> Self {
+        self.floor = Some(texture);
+        self
+    }
+
+    pub fn with_ceiling(mut self, texture: &'a Texture) -> Self {
+        self.ceiling = Some(texture);
+        self.open_sky = false;
+        self
+    }
+
+    pub fn open_sky(mut self) -> Self {
+        self.ceiling = None;
+        self.open_sky = true;
+        self
+    }
+
+    /// Half walls, fences, towers etc. Short walls should also get a ceiling or open sky
+    /// to fill in the space above them, and show their top when the camera is above them
+    pub fn with_height(mut self, height: f32) -> Self {
+        self.height = height;
+        self
+    }
+
+    /// Texture for the top of a short wall
+    pub fn with_top(mut self, texture: &'a Texture) -> Self {
+        self.top = Some(texture);
+        self
+    }
+
+    pub fn is_solid(&self) -> bool {
+        self.wall.is_some()
+    }
+}
+
+/// The level grid. Each cell holds a tile id, which indexes into the tile palette
+pub struct Map<'a> {
+    cells: Vec<Vec<usize>>,
+    tiles: Vec<Tile<'a>>,
+    max_height: f32,
+    doors: HashMap<(usize, usize), Door>, // Keyed by cell (x, y)
+}
+
+impl<'a> Map<'a> {
+    /// Panics if a cell uses a tile id that isn't in the palette
+    pub fn new(cells: Vec<Vec<usize>>, tiles: Vec<Tile<'a>>) -> Self {
+        assert!(
+            cells.iter().flatten().all(|&id| id < tiles.len()),
+            "map uses a tile id with no tile definition"
+        );
+
+        let max_height = tiles
+            .iter()
+            .filter(|t| t.is_solid())
+            .fold(0.0, |max: f32, t| max.max(t.height));
+
+        let mut doors = HashMap::new();
+        for (y, row) in cells.iter().enumerate() {
+            for (x, &id) in row.iter().enumerate() {
+                if tiles[id].door.is_some() {
+                    doors.insert((x, y), Door::new());
+                }
+            }
+        }
+
+        Self {
+            cells,
+            tiles,
+            max_height,
+            doors,
+        }
+    }
+
+    /// The old map format, 0 is an empty cell with no floor or ceiling and
+    /// any other id n is a wall using textures[n - 1]
+    pub fn from_grid(cells: Vec<Vec<usize>>, textures: &[&'a Texture]) -> Self {
+        let mut tiles = vec![Tile::empty().open_sky()];
+        tiles.extend(textures.iter().map(|&t| Tile::wall(t)));
+
+        Self::new(cells, tiles)
+    }
+
+    pub fn width(&self) -> usize {
+        self.cells[0].len()
+    }
+
+    pub fn height(&self) -> usize {
+        self.cells.len()
+    }
+
+    /// Height of the tallest wall tile in the palette
+    pub fn max_height(&self) -> f32 {
+        self.max_height
+    }
+
+    /// Tile for a cell, None if the cell is off the map
+    pub fn tile(&self, x: usize, y: usize) -> Option<&Tile<'a>> {
+        let id = *self.cells.get(y)?.get(x)?;
+        Some(&self.tiles[id])
+    }
+
+    /// Tile under a world position, None if the position is off the map
+    pub fn tile_at(&self, x: f32, y: f32) -> Option<&Tile<'a>> {
+        if x < 0.0 || y < 0.0 {
+            return None;
+        }
+
+        self.tile(x as usize, y as usize)
+    }
+
+    /// Whether a world position is blocked. Everything off the map is solid.
+    /// In a door cell only the slab itself is solid, a thin band along the midline
+    /// covering whatever part of the cell the door hasn't opened yet
+    pub fn is_solid(&self, x: f32, y: f32) -> bool {
+        let tile = match self.tile_at(x, y) {
+            Some(tile) => tile,
+            None => return true,
+        };
+
+        let (fx, fy) = (x - x.floor(), y - y.floor());
+        let on_slab = |across: f32, along: f32, door: &Door| {
+            (across - 0.5).abs() < DOOR_THICKNESS / 2.0 && along >= door.open
+        };
+
+        match (tile.door, self.door(x as usize, y as usize)) {
+            (Some(DoorAxis::X), Some(door)) => on_slab(fy, fx, door),
+            (Some(DoorAxis::Y), Some(door)) => on_slab(fx, fy, door),
+            _ => tile.is_solid(),
+        }
+    }
+
+    /// Whether moving in a straight line from one point to another runs into anything solid.
+    /// The line is checked in steps of at most half a door's thickness, so a long step
+    /// can't jump clean over a door slab
+    pub fn is_blocked(&self, from: Position, to: Position) -> bool {
+        let (dx, dy) = (to.x - from.x, to.y - from.y);
+        let steps = ((dx.hypot(dy) / (DOOR_THICKNESS / 2.0)).ceil() as usize).max(1);
+
+        (1..=steps).any(|i| {
+            let t = i as f32 / steps as f32;
+            self.is_solid(from.x + dx * t, from.y + dy * t)
+        })
+    }
+
+    pub fn door(&self, x: usize, y: usize) -> Option<&Door> {
+        self.doors.get(&(x, y))
+    }
+
+    /// Starts a door opening, or closing if it was already opening.
+    /// Returns false if there's no door in that cell
+    pub fn toggle_door(&mut self, x: usize, y: usize) -> bool {
+        match self.doors.get_mut(&(x, y)) {
+            Some(door) => {
+                door.opening = !door.opening;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Slides every door toward open or closed, call once per frame.
+    /// Doors won't close while anything in occupants is standing in their cell
+    pub fn update_doors(&mut self, dt: Duration, occupants: &[Position]) {
+        for (&(x, y), door) in self.doors.iter_mut() {
+            let blocked = occupants
+                .iter()
+                .any(|p| p.x.floor() == x as f32 && p.y.floor() == y as f32);
+            door.update(dt, blocked);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A door cell at (1, 1) between two open cells, walls everywhere else
+    fn door_map(texture: &Texture) -> Map<'_> {
+        Map::new(
+            vec![vec![1, 1, 1], vec![0, 2, 0], vec![1, 1, 1]],
+            vec![
+                Tile::empty(),
+                Tile::wall(texture),
+                Tile::door(texture, DoorAxis::Y),
+            ],
+        )
+    }
+
+    #[test]
+    fn off_map_and_walls_are_solid() {
+        let texture = Texture::from_color(0);
+        let map = door_map(&texture);
+
+        assert!(map.is_solid(-0.1, 1.5));
+        assert!(map.is_solid(1.5, 3.2));
+        assert!(map.is_solid(1.5, 0.5));
+        assert!(!map.is_solid(0.5, 1.5));
+    }
+
+    #[test]
+    fn closed_door_is_only_solid_along_its_midline() {
+        let texture = Texture::from_color(0);
+        let map = door_map(&texture);
+
+        assert!(map.is_solid(1.5, 1.5));
+        assert!(map.is_solid(1.52, 1.1));
+        assert!(!map.is_solid(1.2, 1.5));
+        assert!(!map.is_solid(1.8, 1.5));
+    }
+
+    #[test]
+    fn long_step_cannot_skip_over_a_closed_door() {
+        let texture = Texture::from_color(0);
+        let map = door_map(&texture);
+        let (from, to) = (Position { x: 1.3, y: 1.5 }, Position { x: 1.7, y: 1.5 });
+
+        assert!(!map.is_solid(to.x, to.y));
+        assert!(map.is_blocked(from, to));
+        assert!(!map.is_blocked(Position { x: 0.2, y: 1.5 }, Position { x: 0.8, y: 1.2 }));
+    }
+
+    #[test]
+    fn open_part_of_door_is_passable() {
+        let texture = Texture::from_color(0);
+        let mut map = door_map(&texture);
+
+        assert!(map.toggle_door(1, 1));
+        map.update_doors(Duration::from_secs_f32(0.5 / DOOR_SPEED), &[]);
+
+        assert!(!map.is_solid(1.5, 1.25));
+        assert!(map.is_solid(1.5, 1.75));
+    }
+
+    #[test]
+    fn door_waits_to_close_while_occupied() {
+        let texture = Texture::from_color(0);
+        let mut map = door_map(&texture);
+
+        map.toggle_door(1, 1);
+        map.update_doors(Duration::from_secs_f32(1.0 / DOOR_SPEED), &[]);
+        map.toggle_door(1, 1);
+
+        let player = Position { x: 1.5, y: 1.3 };
+        map.update_doors(Duration::from_secs(1), &[player]);
+        assert_eq!(map.door(1, 1).unwrap().open, 1.0);
+
+        map.update_doors(Duration::from_secs(1), &[]);
+        assert_eq!(map.door(1, 1).unwrap().open, 0.0);
+    }
+
+    #[test]
+    fn toggle_door_without_door_fails() {
+        let texture = Texture::from_color(0);
+        let mut map = door_map(&texture);
+
+        assert!(!map.toggle_door(0, 1));
+    }
+}
@@ -1,74 +1,177 @@
 #![allow(dead_code)]
 
 pub mod cameraspec;
+pub mod lighting;
+pub mod raycast;
 
 use image::{DynamicImage, GenericImageView};
-use minifb::{Key, Window, WindowOptions};
-use std::{path::Path};
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
+use std::path::Path;
 
 use crate::gamelogic::Moveable;
+use crate::map::{Map, Tile};
+use lighting::{Flashlight, LightLevel, Lighting};
+use raycast::{GridRay, RayHit, WallSide};
 
-const RAY_FINENESS: f32 = 100.0;
+/// Height of the camera above the floor when standing, in world units (walls are 1 unit tall by default)
+const DEFAULT_EYE_HEIGHT: f32 = 0.5;
 
+/// Sprites closer than this along the view direction are projected as if they were this far.
+/// Past this point the projection blows up and a sprite would cover the whole screen with a handful of texels
+const NEAR_PLANE: f32 = 0.2;
+
+/// Horizontal field of view cameras start with
+const DEFAULT_FOV_DEGREES: f32 = 60.0;
+
+/// Furthest the horizon can be pushed from the middle of the screen, as a fraction of its height
+const MAX_PITCH: f32 = 0.5;
+
+/// How far each channel of a pixel can be from a color key and still be keyed out.
+/// Lossy images like jpgs never store the key color exactly, leaving an opaque fringe otherwise
+const COLOR_KEY_TOLERANCE: u8 = 16;
+
+/// Everything the camera draws ends up in here. A canvas either presents to a
+/// minifb window or, when made with `Canvas::headless`, only keeps the frame in memory
 pub struct Canvas {
-    window: Window,
-    buffer: Buffer2D,
+    window: Option<Window>,
+    buffer: Buffer2D<u32>,
     pub width: usize,
     pub height: usize,
     screen_buffer: Vec<u32>,
-    depth_buffer: Vec<f32>,
+    depth_buffer: Buffer2D<f32>,
+    threads: usize, // How many threads the column passes get split across
 }
 
 impl Canvas {
     pub fn new(name: &'static str, width: usize, height: usize) -> Result<Self, minifb::Error> {
-        Ok(Self {
-            window: Window::new(name, width, height, WindowOptions::default())?,
+        let mut canvas = Self::headless(width, height);
+        canvas.window = Some(Window::new(name, width, height, WindowOptions::default())?);
+        Ok(canvas)
+    }
+
+    /// Canvas without a window, for rendering with no display (CI, screenshots, etc)
+    pub fn headless(width: usize, height: usize) -> Self {
+        Self {
+            window: None,
             buffer: Buffer2D::new(height, width),
             width,
             height,
             screen_buffer: vec![0; width * height],
-            depth_buffer: vec![std::f32::MAX; width], // Depth for each column on the canvas
-        })
+            depth_buffer: Buffer2D::new_filled(height, width, f32::MAX), // Depth for each pixel on the canvas
+            threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
+        }
+    }
+
+    pub fn is_headless(&self) -> bool {
+        self.window.is_none()
     }
 
+    /// Presents the frame if there is a window, then clears the canvas for the next frame
     pub fn update(&mut self) {
-        self.buffer.to_screen(&mut self.screen_buffer);
-        let _ = self
-            .window
-            .update_with_buffer(&self.screen_buffer, self.width, self.height);
+        if let Some(window) = &mut self.window {
+            self.buffer.to_screen(&mut self.screen_buffer);
+            let _ = window.update_with_buffer(&self.screen_buffer, self.width, self.height);
+        }
         self.buffer.flush();
         self.flush_depth();
     }
 
-    /// Resets the depth buffer for every column to be at max depth
-    fn flush_depth(&mut self) {
-        for c in &mut self.depth_buffer {
-            *c = std::f32::MAX;
+    /// Writes the current frame to a png. Call this before `update`, which clears the frame
+    pub fn save_png<P: AsRef<Path>>(&mut self, path: P) -> image::ImageResult<()> {
+        self.buffer.to_screen(&mut self.screen_buffer);
+
+        let mut bytes = Vec::with_capacity(self.screen_buffer.len() * 3);
+        for &color in &self.screen_buffer {
+            bytes.extend_from_slice(&[(color >> 16) as u8, (color >> 8) as u8, color as u8]);
         }
+
+        image::save_buffer(
+            path,
+            &bytes,
+            self.width as u32,
+            self.height as u32,
+            image::ExtendedColorType::Rgb8,
+        )
     }
 
+    /// Defaults to however many threads the machine can run at once
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+
+    /// Runs draw on every column of a viewport, split into one contiguous range of columns
+    /// per thread. draw gets the column index within the viewport along with just the part
+    /// of that column's pixels and depths that's inside it. Anything off the canvas is skipped,
+    /// so the slices can be shorter than the viewport is tall
+    fn par_columns<F>(&mut self, viewport: Viewport, draw: F)
+    where
+        F: Fn(usize, &mut [u32], &mut [f32]) + Sync,
+    {
+        let viewport = viewport.clip(self.width, self.height);
+        let chunk = viewport.width.div_ceil(self.threads).max(1);
+        let rows = viewport.y..viewport.y + viewport.height;
+        let columns = viewport.x..viewport.x + viewport.width;
+        let draw = &draw;
+
+        std::thread::scope(|scope| {
+            let colors = self.buffer.0[columns.clone()].chunks_mut(chunk);
+            let depths = self.depth_buffer.0[columns].chunks_mut(chunk);
+
+            for (i, (colors, depths)) in colors.zip(depths).enumerate() {
+                let rows = rows.clone();
+                scope.spawn(move || {
+                    for (k, (column, depth)) in colors.iter_mut().zip(depths).enumerate() {
+                        draw(
+                            i * chunk + k,
+                            &mut column[rows.clone()],
+                            &mut depth[rows.clone()],
+                        );
+                    }
+                });
+            }
+        });
+    }
+
+    /// Resets the depth buffer for every pixel to be at max depth
+    fn flush_depth(&mut self) {
+        self.depth_buffer.fill(f32::MAX);
+    }
+
+    /// Always false for a headless canvas
     pub fn is_key_down(&self, key: Key) -> bool {
-        self.window.is_key_down(key)
+        self.window.as_ref().is_some_and(|w| w.is_key_down(key))
+    }
+
+    /// Only true on the frame the key went down. Always false for a headless canvas
+    pub fn is_key_pressed(&self, key: Key) -> bool {
+        self.window
+            .as_ref()
+            .is_some_and(|w| w.is_key_pressed(key, KeyRepeat::No))
     }
 
     pub fn set_target_fps(&mut self, fps: usize) {
-        self.window.set_target_fps(fps);
+        if let Some(window) = &mut self.window {
+            window.set_target_fps(fps);
+        }
     }
 }
 
 pub struct Skybox {
-    image: DynamicImage,
+    texels: Vec<u32>, // Packed rgb, column major like textures
     width: u32,
     height: u32,
 }
 
 impl Skybox {
     pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
-        let image = image::open(path)?;
-        let (width, height) = image.dimensions();
+        let TextureLevel {
+            width,
+            height,
+            texels,
+        } = TextureLevel::from_image(&image::open(path)?);
 
         Ok(Skybox {
-            image,
+            texels: texels.iter().map(|t| t & 0xFFFFFF).collect(),
             width,
             height,
         })
@@ -83,9 +186,50 @@ impl Skybox {
         let mut v = ((1.0 - vertical_ratio.clamp(0.0, 1.0)) * self.height as f32) as u32;
         v = v.min(self.height - 1);
 
-        let pixel = self.image.get_pixel(u, v);
+        self.texels[(u * self.height + v) as usize]
+    }
+}
+
+/// One wall the ray hit in a column, everything needed to draw it
+struct WallSlice<'a> {
+    hit: RayHit,
+    texture: &'a Texture,
+    height: f32,
+    light: LightLevel,
+    cap: Option<WallCap<'a>>,
+}
+
+/// The top of a wall lower than the eye, which can be seen from the wall's face
+/// back to where the ray leaves the wall's cell
+struct WallCap<'a> {
+    texture: &'a Texture,
+    far_perp_distance: f32,
+}
+
+/// A rectangle of a canvas for a camera to draw into, in pixels from the top left corner.
+/// Lets several cameras share one canvas for split screen or picture in picture
+#[derive(Clone, Copy)]
+pub struct Viewport {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Viewport {
+    pub fn new(x: usize, y: usize, width: usize, height: usize) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
 
-        from_u8_rgb(pixel[0], pixel[1], pixel[2])
+    /// The part of this viewport that's actually on a canvas of the given size
+    fn clip(self, width: usize, height: usize) -> Self {
+        let (x, y) = (self.x.min(width), self.y.min(height));
+        Self::new(x, y, self.width.min(width - x), self.height.min(height - y))
     }
 }
 
@@ -98,10 +242,15 @@ pub struct Position {
 pub struct Camera {
     position: Position,
     pub view_angle: f32, // Principal axis is facing right, deviation is in radians.
-    focal_distance: f32,
-    viewport_size: f32,
-    ray_fineness: f32,
+    fov: f32,            // Horizontal field of view in radians
     camera_fog: cameraspec::CameraFog,
+    viewport: Option<Viewport>,
+    eye_height: f32,
+    eye_offset: f32, // Crouching or jumping, on top of eye_height
+    pitch: f32,
+    sky_fog: cameraspec::SkyFog,
+    flashlight: Option<Flashlight>,
+    side_shading: cameraspec::SideShading,
 }
 
 impl Moveable for Camera {
@@ -137,269 +286,610 @@ impl Moveable for Camera {
         }
         self.view_angle = (theta + self.view_angle) % (2.0 * std::f32::consts::PI);
     }
+    fn update_pitch(&mut self, delta: f32) {
+        self.set_pitch(self.pitch + delta);
+    }
+    fn set_vertical_offset(&mut self, offset: f32) {
+        self.eye_offset = offset;
+    }
 }
 
 impl Camera {
-    pub fn new(fd: f32, vs: f32, rf: f32) -> Self {
+    pub fn new(fov_degrees: f32) -> Self {
         Self {
             position: Position { x: 0.0, y: 0.0 },
             view_angle: 0.0,
-            focal_distance: fd,
-            viewport_size: vs,
-            ray_fineness: rf,
+            fov: clamp_fov(fov_degrees).to_radians(),
             camera_fog: cameraspec::CameraFog::None,
+            viewport: None,
+            eye_height: DEFAULT_EYE_HEIGHT,
+            eye_offset: 0.0,
+            pitch: 0.0,
+            sky_fog: cameraspec::SkyFog::default(),
+            flashlight: None,
+            side_shading: cameraspec::SideShading::None,
         }
     }
 
-    pub fn draw_skybox(&mut self, canvas: &mut Canvas, skybox: &Skybox) {
-        for x in 0..canvas.width {
-            let screen_x = (x as f32 / canvas.width as f32 - 0.5) * self.viewport_size;
-            let ray_angle = self.view_angle + (screen_x / self.focal_distance).atan();
+    pub fn draw_skybox(&self, canvas: &mut Canvas, skybox: &Skybox) {
+        let viewport = self.viewport_on(canvas);
+        let (width, height) = (viewport.width, viewport.height);
+        let horizon = self.horizon(height);
+
+        canvas.par_columns(viewport, |x, column, _| {
+            let ray_angle = self.ray_angle(x, width);
 
-            for y in 0..(canvas.height / 2) {
-                let vertical_ratio = y as f32 / (canvas.height / 2) as f32;
-                let color = skybox.get_pixel(ray_angle, vertical_ratio);
-                canvas.buffer.0[x][y] = color;
+            for (y, pixel) in column
+                .iter_mut()
+                .enumerate()
+                .take(horizon.max(0.0) as usize)
+            {
+                *pixel = self.sky_pixel(skybox, ray_angle, y, height);
             }
-        }
+        });
     }
 
-    pub fn draw_simple_floor(&mut self, canvas: &mut Canvas, color: u32) {
-        for i in 0..canvas.width {
-            for k in (canvas.height / 2)..canvas.height {
-                canvas.buffer.0[i][k] = decrease_brightness(color, canvas.height as u32 - k as u32);
-            }
-        }
+    /// Looking up or down shears the view instead of rotating it, the horizon just slides.
+    /// Positive pitch looks up
+    pub fn pitch(&self) -> f32 {
+        self.pitch
     }
 
-    /// This function is the main rendering function of the camera. Renders the map, draws fog optionally
-    /// must be used or changed for things that interact with the map, ie sprites or fog
-    /// (fog being rendered depends on whether or not it is broken by a piece of wall)
-    pub fn main(&self, canvas: &mut Canvas, map: &Vec<Vec<usize>>, textures: &[&Texture]) {
-        for c in 0..canvas.width {
-            // Calculate ray angle for this column
-            let screen_x = (c as f32 / canvas.width as f32 - 0.5) * self.viewport_size;
-            let ray_angle = self.view_angle + (screen_x / self.focal_distance).atan();
-
-            // Ray direction
-            let dx = ray_angle.cos() / self.ray_fineness;
-            let dy = ray_angle.sin() / self.ray_fineness;
-
-            let mut ray_x = self.position.x;
-            let mut ray_y = self.position.y;
-
-            while ray_x <= map[0].len() as f32 - 1.0
-                && ray_x >= 0.0
-                && ray_y <= map.len() as f32 - 1.0
-                && ray_y >= 0.0
-            {
-                let (ray_x_floor, ray_y_floor) = (ray_x.floor(), ray_y.floor());
-
-                // Various height and distance values
-                // TODO: This now runs every single ray step = SLOW, perform some optimizations
-                let distance = ((ray_x - self.position.x).powf(2.0)
-                    + (ray_y - self.position.y).powf(2.0))
-                .sqrt();
-                let corrected_distance = distance * (screen_x / self.focal_distance as f32).cos();
-
-                let h = (canvas.height as f32 / corrected_distance) as u32;
-                let h_bounded = h.min(canvas.height as u32);
-                let offset = (canvas.height - h_bounded as usize) / 2;
-
-                use cameraspec::CameraFog;
-                match self.camera_fog {
-                    CameraFog::VisibleDistance {
-                        fog_dist,
-                        fog_color,
-                    } => {
-                        // Note that these are the scoped values from the enum
-                        if distance > fog_dist {
-                            for i in 0..offset + h_bounded as usize {
-                                canvas.buffer.0[c][i] = fog_color;
-                            }
+    /// Clamped to MAX_PITCH either way, any further and the shearing gets obvious
+    pub fn set_pitch(&mut self, pitch: f32) {
+        self.pitch = pitch.clamp(-MAX_PITCH, MAX_PITCH);
+    }
 
-                            break; // Skip rest of rendering for this column, fog covers it
-                        }
-                    }
-                    CameraFog::None => (),
-                }
+    /// Standing eye height, crouching and jumping move the eye relative to this
+    pub fn eye_height(&self) -> f32 {
+        self.eye_height
+    }
 
-                if map[ray_y_floor as usize][ray_x_floor as usize] != 0 {
-                    // Lets quickly see if we should draw this
-                    if canvas.depth_buffer[c] < corrected_distance {
-                        break;
-                    }
+    pub fn set_eye_height(&mut self, eye_height: f32) {
+        self.eye_height = eye_height;
+    }
 
-                    canvas.depth_buffer[c] = corrected_distance;
+    /// Where the eye actually is right now, kept just under the ceiling height of 1.0
+    /// so the ceiling plane never ends up level with or below it
+    fn eye(&self) -> f32 {
+        (self.eye_height + self.eye_offset).clamp(0.01, 0.99)
+    }
 
-                    // Texturing, u and v values found and used
-                    let mut color: u32;
+    /// None draws to the whole canvas
+    pub fn viewport(&self) -> Option<Viewport> {
+        self.viewport
+    }
 
-                    let u = (|| {
-                        let ray_x_u = ray_x - ray_x_floor;
-                        let ray_y_u = ray_y - ray_y_floor;
+    pub fn set_viewport(&mut self, viewport: Option<Viewport>) {
+        self.viewport = viewport;
+    }
 
-                        if ray_x_u < 1.0 / RAY_FINENESS || ray_x_u > (1.0 - 1.0 / RAY_FINENESS) {
-                            return ray_y_u;
-                        }
+    /// Where on the canvas this camera draws. It can run off the edges of the canvas,
+    /// the projection still comes from its full size and only the part on the canvas is drawn
+    fn viewport_on(&self, canvas: &Canvas) -> Viewport {
+        self.viewport
+            .unwrap_or(Viewport::new(0, 0, canvas.width, canvas.height))
+    }
 
-                        ray_x_u
-                    })();
+    /// Screen row the horizon lands on for a viewport this tall
+    fn horizon(&self, height: usize) -> f32 {
+        (0.5 + self.pitch) * height as f32
+    }
 
-                    // Going to step for every v for each pixel being drawn
-                    let v_step: f32 = 1.0 / h as f32;
-                    let mut v: f32 = 0.0;
+    /// Horizontal field of view in degrees
+    pub fn fov(&self) -> f32 {
+        self.fov.to_degrees()
+    }
 
-                    // Check to see if player is too close to see the very top
-                    // Finds the proper initial v value if the top of the texture
-                    // is off screen.
-                    if h > h_bounded {
-                        let d = (h - h_bounded) / 2;
-                        v = d as f32 / h as f32;
-                    }
+    pub fn set_fov(&mut self, fov_degrees: f32) {
+        self.fov = clamp_fov(fov_degrees).to_radians();
+    }
 
-                    // If fog is being rendered, we also want it to appear above the block
-                    // This will make a skybox useless, so might add some sort of transparency as we go up
-                    if let CameraFog::VisibleDistance { fog_color, .. } = &self.camera_fog {
-                        for i in 0..offset {
-                            canvas.buffer.0[c][i] = *fog_color; // Testing additive colors in fog for the skybox
-                        }
+    /// Pixels per world unit at a perpendicular distance of 1.0, on both axes so nothing
+    /// gets stretched. Comes from fitting the field of view across the canvas width
+    fn projection(&self, width: usize) -> f32 {
+        width as f32 / (2.0 * (self.fov / 2.0).tan())
+    }
+
+    /// Angle of the ray going through column c
+    fn ray_angle(&self, c: usize, width: usize) -> f32 {
+        let screen_x = c as f32 - width as f32 / 2.0;
+        self.view_angle + (screen_x / self.projection(width)).atan()
+    }
+
+    /// Perspective floor and ceiling casting. Every pixel below the horizon is projected back
+    /// onto the floor plane and textured by its world position, one texture tile per map cell,
+    /// using whatever floor the map tile there has. Ceilings mirror this above the horizon, and
+    /// tiles that are open to the sky (or positions off the map) show the skybox if one is given
+    pub fn draw_floor(
+        &self,
+        canvas: &mut Canvas,
+        map: &Map,
+        skybox: Option<&Skybox>,
+        lighting: &Lighting,
+    ) {
+        let viewport = self.viewport_on(canvas);
+        let (width, height) = (viewport.width, viewport.height);
+        let horizon = self.horizon(height);
+        let eye = self.eye();
+        let projection = self.projection(width);
+
+        canvas.par_columns(viewport, |c, column, _| {
+            let ray_angle = self.ray_angle(c, width);
+            let ray_offset = ray_angle - self.view_angle;
+
+            // Scales a perpendicular distance into a distance along this column's ray
+            let dir_x = ray_angle.cos() / ray_offset.cos();
+            let dir_y = ray_angle.sin() / ray_offset.cos();
+
+            for (k, pixel) in column.iter_mut().enumerate() {
+                // A wall at perp distance d has its base at horizon + eye * projection / d
+                // so we just solve that for d. Rows above the horizon do the same for the ceiling,
+                // which is 1.0 - eye above the camera. A row centered on the horizon would be
+                // infinitely far away, so it's treated as half a pixel off it
+                let p = k as f32 + 0.5 - horizon;
+                let plane = if p > 0.0 { eye } else { 1.0 - eye };
+                let perp_distance = plane * projection / p.abs().max(0.5);
+
+                let world_x = self.position.x + dir_x * perp_distance;
+                let world_y = self.position.y + dir_y * perp_distance;
+                let (u, v) = (world_x - world_x.floor(), world_y - world_y.floor());
+                let distance = perp_distance / ray_offset.cos();
+                let scale = projection / perp_distance; // Pixels per texture at this row
+
+                let tile = map.tile_at(world_x, world_y);
+                let light = self.light_at(lighting, map, world_x, world_y);
+
+                if p > 0.0 {
+                    if let Some(floor) = tile.and_then(|t| t.floor) {
+                        let color = light.apply(floor.get_pixel_uv(u, v, floor.mip_level(scale)));
+                        *pixel = self.camera_fog.apply_at(color, distance, eye, 0.0);
                     }
+                    continue;
+                }
 
-                    for i in offset..offset + h_bounded as usize {
-                        color = textures[map[ray_y_floor as usize][ray_x_floor as usize] - 1]
-                            .get_pixel_uv(u, v);
-                        canvas.buffer.0[c][i] = decrease_brightness(
-                            color,
-                            ((distance + 2.0) * (distance + 2.0) * 2.5) as u32,
-                        ); // 2.5 is the shadow adjustment
-                        v += v_step;
+                match tile {
+                    Some(Tile {
+                        ceiling: Some(ceiling),
+                        ..
+                    }) => {
+                        let level = ceiling.mip_level(scale);
+                        let color = light.apply(ceiling.get_pixel_uv(u, v, level));
+                        *pixel = self.camera_fog.apply_at(color, distance, eye, 1.0);
+                    }
+                    Some(Tile {
+                        open_sky: false, ..
+                    }) => (),
+                    _ => {
+                        if let Some(skybox) = skybox {
+                            *pixel = self.sky_pixel(skybox, ray_angle, k, height);
+                        }
                     }
-                    break;
                 }
+            }
+        });
+    }
+
+    /// Skybox color for a row above the horizon, with the sky fog blended in
+    fn sky_pixel(&self, skybox: &Skybox, ray_angle: f32, row: usize, height: usize) -> u32 {
+        let above = self.horizon(height) - row as f32;
+
+        // The skybox spans the highest the horizon can go, so pitching up scrolls
+        // through it rather than stretching its top row
+        let sky_height = (0.5 + MAX_PITCH) * height as f32;
+        let color = skybox.get_pixel(ray_angle, 1.0 - above / sky_height);
+
+        // Fog is measured from the horizon in half screens, like at level pitch
+        self.fog_sky(color, above / (height as f32 / 2.0))
+    }
 
-                ray_x += dx;
-                ray_y += dy;
+    /// Blends fog into a sky pixel, height is 0.0 at the horizon and 1.0 at the top of the screen
+    fn fog_sky(&self, color: u32, height: f32) -> u32 {
+        match self.camera_fog.color() {
+            Some(fog_color) => {
+                let amount = self.camera_fog.amount(f32::INFINITY) * self.sky_fog.amount(height);
+                blend(color, fog_color, (amount * 255.0) as u8)
             }
+            None => color,
         }
     }
 
-    pub fn render_sprites(&self, canvas: &mut Canvas, sprites: &[&Sprite]) {
-        for &s in sprites {
-            let x = s.position.x - self.position.x;
-            let y = s.position.y - self.position.y;
-
-            // For some reason the view angle starts with a pi/2 offset relative to the
-            // angle we want here. This gives an angle I can easily use to compare with the sprite angle
-            let adjusted_va =
-                (self.view_angle + std::f32::consts::FRAC_PI_2 + std::f32::consts::PI)
-                    % (2.0 * std::f32::consts::PI);
-
-            let mut sprite_angle = (x.abs() / y.abs()).atan();
-            // Convert the angle given into [0, 2pi]
-            if y < 0.0 && x > 0.0 {
-                sprite_angle = std::f32::consts::PI - sprite_angle;
-            } else if y > 0.0 && x < 0.0 {
-                sprite_angle = (2.0 * std::f32::consts::PI) - sprite_angle;
-            } else if y < 0.0 && x < 0.0 {
-                sprite_angle += std::f32::consts::PI;
+    pub fn flashlight(&self) -> Option<&Flashlight> {
+        self.flashlight.as_ref()
+    }
+
+    pub fn flashlight_mut(&mut self) -> Option<&mut Flashlight> {
+        self.flashlight.as_mut()
+    }
+
+    /// Swaps out the flashlight, None takes it away
+    pub fn set_flashlight(&mut self, flashlight: Option<Flashlight>) {
+        self.flashlight = flashlight;
+    }
+
+    /// Light from the scene plus the camera's flashlight at a point in the world
+    fn light_at(&self, lighting: &Lighting, map: &Map, x: f32, y: f32) -> LightLevel {
+        let level = lighting.level_at(map, x, y);
+        match &self.flashlight {
+            Some(flashlight) => {
+                level.add(flashlight.level_at(self.position, self.view_angle, x, y))
             }
+            None => level,
+        }
+    }
 
-            // For reasons I don't feel like looking into, the angle im getting here
-            // is rotating counter clockewise from a birds eye view. This swaps the direction.
-            sprite_angle = (2.0 * std::f32::consts::PI) - sprite_angle;
+    /// Applies the camera's side shading for walls facing this way
+    fn shade_side(&self, color: u32, side: WallSide) -> u32 {
+        use cameraspec::SideShading;
+        match (self.side_shading, side) {
+            (SideShading::NorthSouth(amount), WallSide::North | WallSide::South)
+            | (SideShading::EastWest(amount), WallSide::East | WallSide::West) => {
+                decrease_brightness(color, amount)
+            }
+            _ => color,
+        }
+    }
 
-            //println!("{} : {}", sprite_angle, adjusted_va);
+    /// This function is the main rendering function of the camera. Renders the map, draws fog optionally
+    /// must be used or changed for things that interact with the map, ie sprites or fog
+    /// (fog being rendered depends on whether or not it is broken by a piece of wall)
+    /// Fog over the sky is handled where the sky is drawn, see `SkyFog`
+    ///
+    /// Walls are drawn front to back. Anything below the top of a wall that's already been drawn
+    /// is hidden, so the ray keeps going past short walls to find taller ones behind them.
+    /// Walls with see-through textures (fences, grates, windows) don't stop the ray either,
+    /// they're held onto and blended over everything behind them once the column is done
+    pub fn main(&self, canvas: &mut Canvas, map: &Map, lighting: &Lighting) {
+        let viewport = self.viewport_on(canvas);
+        let (width, height) = (viewport.width, viewport.height);
+        let horizon = self.horizon(height);
+        let eye = self.eye();
+        let projection = self.projection(width);
+
+        canvas.par_columns(viewport, |c, column, depth| {
+            let ray_angle = self.ray_angle(c, width);
+            let ray_offset = ray_angle - self.view_angle;
+
+            let ray = GridRay::new(
+                self.position,
+                ray_angle,
+                self.view_angle,
+                map.width(),
+                map.height(),
+            );
+
+            let (dir_x, dir_y) = (ray_angle.cos(), ray_angle.sin());
+
+            // Depth left over from another camera drawing here doesn't count
+            depth.fill(f32::MAX);
+
+            // Every row from here down has already been drawn to
+            let mut clip = column.len();
+            let mut masked: Vec<WallSlice> = Vec::new();
+
+            // The ray never reports the cell it starts in, which only matters when
+            // the camera is standing in a door's cell with the slab still ahead of it
+            let start = ray.start().filter(|hit| {
+                map.tile(hit.cell_x, hit.cell_y)
+                    .is_some_and(|tile| tile.door.is_some())
+            });
+
+            for hit in start.into_iter().chain(ray) {
+                if let (Some(fog_dist), Some(fog_color)) =
+                    (self.camera_fog.solid_distance(), self.camera_fog.color())
+                {
+                    if hit.distance > fog_dist {
+                        // Fog acts like a wall placed at exactly where it turns solid, as tall as the
+                        // tallest wall. The sky above it is left alone so it can show through
+                        let scale = projection / (fog_dist * ray_offset.cos());
+                        let fog_top = horizon + (eye - map.max_height().max(1.0)) * scale;
+                        let fog_bottom = horizon + eye * scale;
+
+                        let first_row = fog_top.max(0.0) as usize;
+                        for pixel in
+                            &mut column[first_row..(fog_bottom.max(0.0) as usize).min(clip)]
+                        {
+                            *pixel = fog_color;
+                        }
 
-            let (left_bound, right_bound, sector_angle) = {
-                // This gets the angle between the camera border and the center focal line
-                // Then used to find the angles of the two camera borders globally
-                let deviation = (self.viewport_size / 2.0 / self.focal_distance).atan();
+                        break; // Skip rest of rendering for this column, fog covers it
+                    }
+                }
 
-                let mut left_bound = adjusted_va - deviation;
-                if left_bound < 0.0 {
-                    left_bound = (2.0 * std::f32::consts::PI) + left_bound;
+                let (tile, faces) = match map.tile(hit.cell_x, hit.cell_y) {
+                    Some(
+                        tile @ Tile {
+                            wall: Some(faces), ..
+                        },
+                    ) => (tile, faces),
+                    _ => continue,
+                };
+
+                // Doors are a thin slab in the middle of the cell, so the hit moves to the slab
+                let hit = match (tile.door, map.door(hit.cell_x, hit.cell_y)) {
+                    (Some(axis), Some(door)) => {
+                        match hit.through_door(dir_x, dir_y, axis, door.open) {
+                            Some(door_hit) => door_hit,
+                            None => continue,
+                        }
+                    }
+                    _ => hit,
+                };
+
+                let texture = faces.face(hit.side);
+
+                // Only solid walls get a top, the slab of a door has nothing to stand on
+                let cap =
+                    (tile.height < eye && tile.door.is_none() && !texture.is_masked()).then(|| {
+                        // Where the ray crosses the far x and y sides of the cell, whichever is first
+                        let exit = |cell: usize, origin: f32, dir: f32| {
+                            if dir.abs() < 1e-6 {
+                                return f32::INFINITY;
+                            }
+                            let side = if dir > 0.0 { cell + 1 } else { cell };
+                            (side as f32 - origin) / dir
+                        };
+                        let far = exit(hit.cell_x, self.position.x, dir_x).min(exit(
+                            hit.cell_y,
+                            self.position.y,
+                            dir_y,
+                        ));
+
+                        WallCap {
+                            texture: tile.top.unwrap_or(texture),
+                            far_perp_distance: far * ray_offset.cos(),
+                        }
+                    });
+
+                let slice = WallSlice {
+                    hit,
+                    texture,
+                    height: tile.height,
+                    light: self.light_at(lighting, map, hit.point.x, hit.point.y),
+                    cap,
+                };
+
+                if slice.texture.is_masked() {
+                    masked.push(slice);
+                    continue;
                 }
 
-                let right_bound = (adjusted_va + deviation) % (2.0 * std::f32::consts::PI);
+                let top = self.draw_wall_slice(column, depth, clip, horizon, projection, &slice);
 
-                (left_bound, right_bound, deviation * 2.0)
-            };
+                clip = clip.min(top);
 
-            // Don't draw a sprite if its not in the visible angle of the camera
-            let c = match is_in_sector(left_bound, right_bound, sprite_angle) {
-                Ok(a) => {
-                    let v = a / sector_angle;
-                    (v * canvas.width as f32) as usize
+                // Nothing behind a wall at least this tall can peek over it
+                if clip == 0 || (tile.height >= map.max_height() && tile.height >= eye) {
+                    break;
                 }
-                Err(()) => continue,
-            };
+            }
 
-            let screen_x = (c as f32 / canvas.width as f32 - 0.5) * self.viewport_size;
-            let distance = (x * x + y * y).sqrt();
-            let corrected_distance = distance * (screen_x / self.focal_distance as f32).cos();
-            let h = (canvas.height as f32 / corrected_distance) as u32;
+            // Back to front, the depth test keeps them behind any nearer solid wall
+            for slice in masked.iter().rev() {
+                self.draw_wall_slice(column, depth, column.len(), horizon, projection, slice);
+            }
+        });
+    }
 
-            let h_bounded = h.min(canvas.height as u32);
-            let mut offset = (canvas.height - h_bounded as usize) / 2;
+    /// Draws the visible part of one wall into a column, only touching rows above clip.
+    /// Partially transparent texels are blended over what's already there, and only
+    /// write depth if they're mostly opaque. Returns the row the top of the wall landed on,
+    /// or the back edge of its top if the wall is low enough to be seen over
+    fn draw_wall_slice(
+        &self,
+        column: &mut [u32],
+        depth: &mut [f32],
+        clip: usize,
+        horizon: f32,
+        projection: f32,
+        slice: &WallSlice,
+    ) -> usize {
+        let WallSlice {
+            hit,
+            texture,
+            height,
+            light,
+            cap,
+        } = slice;
 
-            // Apply scaling magics
-            let scaled_h = (h_bounded as f32 * s.scale) as u32;
-            offset += (h_bounded - scaled_h) as usize;
-            let h_bounded = scaled_h; // Quick reassign
+        let eye = self.eye();
 
-            // Get a correct width
-            let aspect_ratio = s.texture.width as f32 / s.texture.height as f32;
-            let corrected_width = (aspect_ratio * h_bounded as f32).floor() as usize;
+        // Screen rows per world unit at this distance
+        let scale = projection / hit.perp_distance;
+        let bottom = horizon + eye * scale;
+        let top = horizon + (eye - height) * scale;
 
-            // c is the column to draw the sprite on
+        let first_row = top.max(0.0) as usize;
+        let last_row = (bottom.max(0.0) as usize).min(clip);
+        let level = texture.mip_level(scale);
 
-            let mut v = 0.0;
-            let v_step = 1.0 / h_bounded as f32;
+        for i in first_row..last_row {
+            if depth[i] < hit.perp_distance {
+                continue;
+            }
 
-            let mut u;
-            let u_step = 1.0 / corrected_width as f32;
-            let u_bounded: f32;
+            // World height of this pixel above the floor, textures repeat every unit
+            let z = eye - (i as f32 + 0.5 - horizon) / scale;
+            let v = (1.0 - z).rem_euclid(1.0);
 
-            // Bounding to 0 isnt really something to do here because of the unsigned type
-            let mut left = 0;
-            if corrected_width / 2 <= c {
-                left = c - corrected_width / 2;
-                u_bounded = 0.0;
-            } else {
-                // Adjust u
-                u_bounded = ((corrected_width / 2) - c) as f32 * u_step;
+            let (color, alpha) = texture.get_pixel_uv_alpha(hit.u, v, level);
+            if alpha == 0 {
+                continue;
+            }
+
+            let color = self.shade_side(light.apply(color), hit.side);
+            let color = self.camera_fog.apply_at(color, hit.distance, eye, z);
+            column[i] = blend(column[i], color, alpha);
+
+            if alpha >= 128 {
+                depth[i] = hit.perp_distance;
+            }
+        }
+
+        let Some(cap) = cap else {
+            return first_row;
+        };
+
+        // The top is a floor at the wall's height, cast the same way as draw_floor
+        let plane = eye - height;
+        let far_top = horizon + plane * projection / cap.far_perp_distance;
+        let cap_first_row = far_top.max(0.0) as usize;
+
+        for i in cap_first_row..first_row.min(clip) {
+            let p = i as f32 + 0.5 - horizon;
+            let perp_distance =
+                (plane * projection / p).clamp(hit.perp_distance, cap.far_perp_distance);
+            if depth[i] < perp_distance {
+                continue;
+            }
+
+            // Same ray as the hit, just perp_distance along it instead
+            let t = perp_distance / hit.perp_distance;
+            let world_x = self.position.x + (hit.point.x - self.position.x) * t;
+            let world_y = self.position.y + (hit.point.y - self.position.y) * t;
+            let (u, v) = (world_x - world_x.floor(), world_y - world_y.floor());
+
+            let level = cap.texture.mip_level(projection / perp_distance);
+            let color = light.apply(cap.texture.get_pixel_uv(u, v, level));
+            column[i] = self
+                .camera_fog
+                .apply_at(color, hit.distance * t, eye, *height);
+            depth[i] = perp_distance;
+        }
+
+        cap_first_row.min(first_row)
+    }
+
+    /// Sprites are moved into camera space, forward along the view direction and right across it,
+    /// then projected with the same focal distance and viewport as the wall rays so they line up.
+    /// They're drawn back to front and only depth tested, never written, so the depth buffer only
+    /// ever holds walls and nearer sprites simply paint over farther ones
+    pub fn render_sprites(
+        &self,
+        canvas: &mut Canvas,
+        map: &Map,
+        sprites: &[&Sprite],
+        lighting: &Lighting,
+    ) {
+        let viewport = self.viewport_on(canvas);
+        let on_canvas = viewport.clip(canvas.width, canvas.height);
+        if on_canvas.width == 0 || on_canvas.height == 0 {
+            return;
+        }
+
+        let width = viewport.width as f32;
+        let horizon = self.horizon(viewport.height);
+        let eye = self.eye();
+        let projection = self.projection(viewport.width);
+        let (sin, cos) = self.view_angle.sin_cos();
+
+        // Forward and right of each sprite in camera space, anything behind the camera is dropped
+        let mut visible: Vec<(f32, f32, &Sprite)> = sprites
+            .iter()
+            .map(|&s| {
+                let x = s.position.x - self.position.x;
+                let y = s.position.y - self.position.y;
+                (x * cos + y * sin, y * cos - x * sin, s)
+            })
+            .filter(|&(forward, _, _)| forward > 0.0)
+            .collect();
+
+        visible.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        for (forward, right, s) in visible {
+            let texture = s.texture_from(self.position);
+            let distance = forward.hypot(right);
+
+            // Sprites closer than the near plane are drawn as if they were on it
+            let depth = forward.max(NEAR_PLANE);
+
+            // Inverse of the column to ray angle mapping in main
+            let center = width / 2.0 + right / depth * projection;
+
+            // Pixels per world unit at this depth, the same on both axes as for walls
+            let scale = projection / depth;
+
+            // Sprites are scale world units tall, their bottom is elevation above the floor
+            let bottom = horizon + (eye - s.elevation) * scale;
+            let top = bottom - s.scale * scale;
+            let aspect_ratio = texture.width as f32 / texture.height as f32;
+            let half_width = s.scale * aspect_ratio * scale / 2.0;
+            let (left, right) = (center - half_width, center + half_width);
+
+            // Entirely off either edge of the screen, anything partially on is clipped below
+            if right <= 0.0 || left >= width {
+                continue;
             }
 
-            u = u_bounded;
-
-            let right = c + corrected_width / 2;
-            let right_bounded = right.min(canvas.width);
-
-            // Each i, k pair should be a pixel being drawn for the sprite
-            for i in offset..offset + h_bounded as usize {
-                for k in left..right_bounded {
-                    if canvas.depth_buffer[k] < corrected_distance {
-                        u += u_step; // Make sure we still texture correctly
-                        continue; // This will check depth for every column,
-                        // Should rework loop to do this column by column instead of the
-                        // current row by row to stop repeat checks on the columns
-                        // TODO
-                    } else {
-                        canvas.depth_buffer[k] = corrected_distance;
+            let level = texture.mip_level(s.scale * scale);
+            let light = self.light_at(lighting, map, s.position.x, s.position.y);
+
+            let rows = top.max(0.0) as usize..(bottom.max(0.0) as usize).min(on_canvas.height);
+
+            for k in left.max(0.0) as usize..(right.max(0.0) as usize).min(on_canvas.width) {
+                let u = (k as f32 + 0.5 - left) / (right - left);
+                let colors = &mut canvas.buffer.0[viewport.x + k][viewport.y..];
+                let depths = &canvas.depth_buffer.0[viewport.x + k][viewport.y..];
+
+                for i in rows.clone() {
+                    if depths[i] < forward {
+                        continue;
+                    }
+
+                    // Fully transparent texels are skipped
+                    let v = (i as f32 + 0.5 - top) / (bottom - top);
+                    let (color, alpha) = texture.get_pixel_uv_alpha(u, v, level);
+                    if alpha == 0 {
+                        continue;
                     }
-                    canvas.buffer.0[k][i] = s.texture.get_pixel_uv(u, v);
-                    u += u_step;
+
+                    let z = s.elevation + (1.0 - v) * s.scale;
+                    let color = self
+                        .camera_fog
+                        .apply_at(light.apply(color), distance, eye, z);
+                    colors[i] = blend(colors[i], color, alpha);
                 }
-                u = u_bounded;
-                v += v_step;
             }
         }
     }
 }
 
+/// One mip level decoded to packed ARGB, stored column major ([x * height + y])
+/// since the renderer pulls texels in vertical strips
+struct TextureLevel {
+    width: u32,
+    height: u32,
+    texels: Vec<u32>,
+}
+
+impl TextureLevel {
+    fn from_image(image: &DynamicImage) -> Self {
+        let rgba = image.to_rgba8();
+        let (width, height) = rgba.dimensions();
+
+        let mut texels = Vec::with_capacity((width * height) as usize);
+        for x in 0..width {
+            for y in 0..height {
+                let p = rgba.get_pixel(x, y);
+                texels.push(((p[3] as u32) << 24) | from_u8_rgb(p[0], p[1], p[2]));
+            }
+        }
+
+        Self {
+            width,
+            height,
+            texels,
+        }
+    }
+
+    #[inline(always)]
+    fn get(&self, x: u32, y: u32) -> u32 {
+        self.texels[(x * self.height + y) as usize]
+    }
+}
+
 enum TextureOption {
-    Image(DynamicImage),
+    Image(Vec<TextureLevel>), // Mip chain, each level half the size of the last
     Color(u32),
 }
 
@@ -407,18 +897,54 @@ pub struct Texture {
     image: TextureOption,
     width: u32,
     height: u32,
+    masked: bool, // Has at least one texel that isn't fully opaque
 }
 
 impl Texture {
     pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
-        let image = image::open(path)?;
+        Ok(Self::from_image(image::open(path)?))
+    }
+
+    /// Decodes the image and its mip chain up front, the image itself isn't kept
+    pub fn from_image(image: DynamicImage) -> Self {
         let (width, height) = image.dimensions();
+        let mips: Vec<TextureLevel> = build_mip_chain(image)
+            .iter()
+            .map(TextureLevel::from_image)
+            .collect();
+        let masked = mips[0].texels.iter().any(|&t| t >> 24 < 255);
 
-        Ok(Self {
-            image: TextureOption::Image(image),
+        Self {
+            image: TextureOption::Image(mips),
             width,
             height,
-        })
+            masked,
+        }
+    }
+
+    /// Same as load_from_file, but every pixel close to the key color is made fully transparent
+    pub fn load_from_file_keyed<P: AsRef<Path>>(
+        path: P,
+        key: u32,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self::from_image_keyed(image::open(path)?, key))
+    }
+
+    /// For images without an alpha channel, pixels that match key become transparent.
+    /// Each channel only has to be within COLOR_KEY_TOLERANCE of the key's, so keys survive lossy formats.
+    /// The mip chain is filtered with premultiplied alpha, so the key color doesn't bleed into smaller levels
+    pub fn from_image_keyed(image: DynamicImage, key: u32) -> Self {
+        let key = [(key >> 16) as u8, (key >> 8) as u8, key as u8];
+
+        let mut rgba = image.to_rgba8();
+        for p in rgba.pixels_mut() {
+            let keyed = (0..3).all(|i| p[i].abs_diff(key[i]) <= COLOR_KEY_TOLERANCE);
+            if keyed {
+                p[3] = 0;
+            }
+        }
+
+        Self::from_image(DynamicImage::ImageRgba8(rgba))
     }
 
     pub fn from_color(color: u32) -> Self {
@@ -426,48 +952,139 @@ impl Texture {
             image: TextureOption::Color(color),
             width: 1,
             height: 1,
+            masked: false,
         }
     }
 
-    fn get_pixel_uv(&self, u: f32, v: f32) -> u32 {
+    /// Whether anything behind this texture can be seen through it
+    pub fn is_masked(&self) -> bool {
+        self.masked
+    }
+
+    /// Picks the mip level for drawing this texture so that it covers projected_height pixels
+    /// on screen. Each level down halves the texture, so it's just how many times
+    /// the texture needs halving before a texel is about a pixel
+    fn mip_level(&self, projected_height: f32) -> usize {
+        let levels = match &self.image {
+            TextureOption::Image(mips) => mips.len(),
+            TextureOption::Color(_) => return 0,
+        };
+
+        let texels_per_pixel = self.height as f32 / projected_height;
+        if texels_per_pixel <= 1.0 || texels_per_pixel.is_nan() {
+            return 0;
+        }
+
+        (texels_per_pixel.log2() as usize).min(levels - 1)
+    }
+
+    fn get_pixel_uv(&self, u: f32, v: f32, level: usize) -> u32 {
+        self.get_pixel_uv_alpha(u, v, level).0
+    }
+
+    /// Color and alpha at a uv from a mip level, alpha is 255 for textures without an alpha channel
+    fn get_pixel_uv_alpha(&self, u: f32, v: f32, level: usize) -> (u32, u8) {
         // U is relative to x, v is relative to y here
         // I'm using uv because a size of a wall is 1, so we can easily calculate uv with a ray position and wall corner position
 
-        if let TextureOption::Color(c) = &self.image {
-            return *c;
-        }
+        let mip = match &self.image {
+            TextureOption::Color(c) => return (*c, 255),
+            TextureOption::Image(mips) => &mips[level.min(mips.len() - 1)],
+        };
 
-        let x = (u * self.width as f32).floor() as u32;
-        let y = (v * self.height as f32).floor() as u32;
+        // Clamped since floating point error can land u or v exactly on 1.0
+        let x = ((u * mip.width as f32) as u32).min(mip.width - 1);
+        let y = ((v * mip.height as f32) as u32).min(mip.height - 1);
 
-        // Also, I know that I only need to draw columns so this can be heavily optimized but just poc for now
-        if let TextureOption::Image(i) = &self.image {
-            let pixel = i.get_pixel(x, y);
-            return from_u8_rgb(pixel[0], pixel[1], pixel[2]);
+        let texel = mip.get(x, y);
+        (texel & 0xFFFFFF, (texel >> 24) as u8)
+    }
+}
+
+/// Halves the image until it's a single pixel on its longest side.
+/// The first level is the image as it was given.
+/// Smaller levels are filtered with premultiplied alpha, otherwise the color under
+/// transparent texels (like a color key) gets averaged into the visible edges
+fn build_mip_chain(image: DynamicImage) -> Vec<DynamicImage> {
+    let mut level = image.to_rgba32f();
+    for p in level.pixels_mut() {
+        let alpha = p[3];
+        p.0[..3].iter_mut().for_each(|c| *c *= alpha);
+    }
+
+    let mut mips = vec![image];
+
+    loop {
+        let (width, height) = level.dimensions();
+        if width <= 1 && height <= 1 {
+            break;
         }
 
-        0
+        level = image::imageops::resize(
+            &level,
+            (width / 2).max(1),
+            (height / 2).max(1),
+            image::imageops::FilterType::Triangle,
+        );
+
+        let mut straight = level.clone();
+        for p in straight.pixels_mut() {
+            let alpha = p[3];
+            if alpha > 0.0 {
+                p.0[..3].iter_mut().for_each(|c| *c = (*c / alpha).min(1.0));
+            }
+        }
+        mips.push(DynamicImage::ImageRgba32F(straight));
     }
+
+    mips
 }
 
 pub struct Sprite<'a> {
     position: Position,
-    texture: &'a Texture,
+    textures: Vec<&'a Texture>, // One per viewing direction, evenly spaced around the sprite
+    facing: f32,
     scale: f32,
+    elevation: f32, // Height of the bottom of the sprite above the floor
 }
 
 impl<'a> Sprite<'a> {
     pub fn from_texture(texture: &'a Texture) -> Self {
+        Self::from_directions(vec![texture])
+    }
+
+    /// The first texture is seen from straight in front of the sprite, the rest go around it
+    /// in the same direction angles increase. Panics if textures is empty
+    pub fn from_directions(textures: Vec<&'a Texture>) -> Self {
+        assert!(!textures.is_empty(), "sprite needs at least one texture");
+
         Self {
             position: Position { x: 0.0, y: 0.0 },
-            texture: texture,
+            textures,
+            facing: 0.0,
             scale: 1.0,
+            elevation: 0.0,
         }
     }
 
     pub fn scale(&mut self, scale: f32) {
         self.scale = scale;
     }
+
+    /// Lifts the sprite off the floor, in world units. Something hanging from a ceiling of
+    /// height h sits at h - scale, floating items sit anywhere in between
+    pub fn elevation(&mut self, elevation: f32) {
+        self.elevation = elevation;
+    }
+
+    /// Texture for a camera at viewer, picked from the angle between the sprite's facing and the camera
+    fn texture_from(&self, viewer: Position) -> &'a Texture {
+        let to_viewer = (viewer.y - self.position.y).atan2(viewer.x - self.position.x);
+        let step = std::f32::consts::TAU / self.textures.len() as f32;
+        let index = ((to_viewer - self.facing) / step).round() as isize;
+
+        self.textures[index.rem_euclid(self.textures.len() as isize) as usize]
+    }
 }
 
 impl Moveable for Sprite<'_> {
@@ -476,11 +1093,11 @@ impl Moveable for Sprite<'_> {
     }
 
     fn get_angle(&self) -> f32 {
-        0.0 // Redundant for sprites, atleast for now
+        self.facing
     }
 
-    fn set_angle(&mut self, _theta: f32) {
-        // None
+    fn set_angle(&mut self, theta: f32) {
+        self.facing = theta.rem_euclid(std::f32::consts::TAU);
     }
 
     fn set_position(&mut self, x: f32, y: f32) {
@@ -488,8 +1105,8 @@ impl Moveable for Sprite<'_> {
         self.position.y = y;
     }
 
-    fn update_angle(&mut self, _theta: f32) {
-        // None
+    fn update_angle(&mut self, theta: f32) {
+        self.set_angle(self.facing + theta);
     }
 
     fn update_position(&mut self, x: f32, y: f32) {
@@ -498,16 +1115,21 @@ impl Moveable for Sprite<'_> {
     }
 }
 
-pub struct Buffer2D(Vec<Vec<u32>>);
+/// Column major, indexed as [x][y] since everything is drawn in vertical strips
+pub struct Buffer2D<T>(Vec<Vec<T>>);
 
-impl Buffer2D {
+impl<T: Copy + Default> Buffer2D<T> {
     fn new(height: usize, width: usize) -> Self {
-        Self(vec![vec![0; height]; width])
+        Self::new_filled(height, width, T::default())
+    }
+
+    fn new_filled(height: usize, width: usize, value: T) -> Self {
+        Self(vec![vec![value; height]; width])
     }
 
     /// Does this in-place to an existing screen buffer
     /// Also hoping that the buffer is the same size as the Buffer2D
-    fn to_screen(&self, buffer: &mut [u32]) {
+    fn to_screen(&self, buffer: &mut [T]) {
         let mut idx = 0;
         for y in 0..self.0[0].len() {
             for x in 0..self.0.len() {
@@ -518,10 +1140,12 @@ impl Buffer2D {
     }
 
     fn flush(&mut self) {
-        for i in 0..self.0.len() {
-            for k in 0..self.0[0].len() {
-                self.0[i][k] = 0;
-            }
+        self.fill(T::default());
+    }
+
+    fn fill(&mut self, value: T) {
+        for column in &mut self.0 {
+            column.fill(value);
         }
     }
 }
@@ -532,6 +1156,26 @@ fn from_u8_rgb(r: u8, g: u8, b: u8) -> u32 {
     (r << 16) | (g << 8) | b
 }
 
+/// Keeps a field of view in degrees to something that can actually be projected
+fn clamp_fov(fov_degrees: f32) -> f32 {
+    fov_degrees.clamp(1.0, 179.0)
+}
+
+/// Mixes over on top of under, alpha being how much of over shows
+fn blend(under: u32, over: u32, alpha: u8) -> u32 {
+    if alpha == 255 {
+        return over;
+    }
+
+    let a = alpha as u32;
+    let mix = |shift: u32| {
+        let (u, o) = ((under >> shift) & 255, (over >> shift) & 255);
+        ((o * a + u * (255 - a)) / 255) << shift
+    };
+
+    mix(16) | mix(8) | mix(0)
+}
+
 fn decrease_brightness(color: u32, amount: u32) -> u32 {
     let mut r = color >> 16;
     let mut g = (color >> 8) & 255;
@@ -556,21 +1200,454 @@ fn decrease_brightness(color: u32, amount: u32) -> u32 {
     (r << 16) | (g << 8) | b
 }
 
-/// Returns true if an angle is within another two on the unit circle
-/// This function is intended for angles that are locked to [0, 2pi],
-/// thus the logic checks
-///
-/// (Remember, a left bound could technically have a value greater than that of
-/// the right bound if the range straddles the positive y axis.)
-#[inline(always)]
-fn is_in_sector(left_bound: f32, right_bound: f32, angle: f32) -> Result<f32, ()> {
-    if left_bound < right_bound && angle > left_bound && angle < right_bound {
-        return Ok(angle - left_bound);
-    } else if left_bound > right_bound && angle > left_bound && angle > right_bound {
-        return Ok(angle - left_bound);
-    } else if left_bound > right_bound && angle < left_bound && angle < right_bound {
-        return Ok((2.0 * std::f32::consts::PI) - left_bound + angle);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAGENTA: u32 = 0xFF00FF;
+    const RED: u32 = 0xFF0000;
+    const GREEN: u32 = 0x00FF00;
+    const BLUE: u32 = 0x0000FF;
+
+    fn pixel(canvas: &Canvas, x: usize, y: usize) -> u32 {
+        canvas.buffer.0[x][y]
+    }
+
+    // 90 degree camera at (x, y) looking along +x, with no fog and full ambient light
+    fn camera_at(x: f32, y: f32) -> Camera {
+        let mut camera = Camera::new(90.0);
+        camera.set_position(x, y);
+        camera
+    }
+
+    // Nothing but empty tiles, big enough that the tests never look off its edge
+    fn open_map() -> Map<'static> {
+        Map::new(vec![vec![0; 8]; 8], vec![Tile::empty()])
+    }
+
+    fn sprite_at(texture: &Texture, x: f32, y: f32) -> Sprite<'_> {
+        let mut sprite = Sprite::from_texture(texture);
+        sprite.set_position(x, y);
+        sprite
+    }
+
+    #[test]
+    fn keyed_color_does_not_bleed_into_mips() {
+        // White and magenta columns, the magenta is keyed out
+        let image = image::RgbaImage::from_fn(8, 8, |x, _| {
+            if x % 2 == 0 {
+                image::Rgba([255, 255, 255, 255])
+            } else {
+                image::Rgba([255, 0, 255, 255])
+            }
+        });
+        let texture = Texture::from_image_keyed(DynamicImage::ImageRgba8(image), MAGENTA);
+
+        let TextureOption::Image(mips) = &texture.image else {
+            panic!("keyed texture should be an image");
+        };
+        assert_eq!(mips.len(), 4);
+
+        for mip in &mips[1..] {
+            for &texel in &mip.texels {
+                assert!(texel >> 24 > 0);
+                assert_eq!(texel & 0xFFFFFF, 0xFFFFFF, "level texel {texel:08X}");
+            }
+        }
+    }
+
+    fn blank(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgba8(image::RgbaImage::new(width, height))
+    }
+
+    #[test]
+    fn color_key_matches_near_misses_from_lossy_images() {
+        let colors = [[255, 0, 255], [247, 9, 250], [200, 0, 200], [255, 255, 255]];
+        let image = image::RgbImage::from_fn(4, 1, |x, _| image::Rgb(colors[x as usize]));
+        let texture = Texture::from_image_keyed(DynamicImage::ImageRgb8(image), MAGENTA);
+
+        let TextureOption::Image(mips) = &texture.image else {
+            panic!("keyed texture should be an image");
+        };
+        let alphas: Vec<u32> = mips[0].texels.iter().map(|t| t >> 24).collect();
+        assert_eq!(alphas, vec![0, 0, 255, 255]);
     }
 
-    Err(())
+    #[test]
+    fn mip_level_follows_projected_size() {
+        let texture = Texture::from_image(blank(64, 64));
+
+        assert_eq!(texture.mip_level(256.0), 0);
+        assert_eq!(texture.mip_level(64.0), 0);
+        assert_eq!(texture.mip_level(32.0), 1);
+        assert_eq!(texture.mip_level(20.0), 1);
+        assert_eq!(texture.mip_level(16.0), 2);
+        assert_eq!(texture.mip_level(1.0), 6);
+        assert_eq!(texture.mip_level(0.01), 6);
+        assert_eq!(texture.mip_level(0.0), 6);
+        assert_eq!(Texture::from_color(RED).mip_level(1.0), 0);
+    }
+
+    #[test]
+    fn mip_chain_halves_down_to_a_single_pixel() {
+        let sizes = |width, height| -> Vec<(u32, u32)> {
+            build_mip_chain(blank(width, height))
+                .iter()
+                .map(|mip| mip.dimensions())
+                .collect()
+        };
+
+        assert_eq!(sizes(4, 4), vec![(4, 4), (2, 2), (1, 1)]);
+        assert_eq!(sizes(10, 3), vec![(10, 3), (5, 1), (2, 1), (1, 1)]);
+        assert_eq!(
+            sizes(16, 64),
+            vec![(16, 64), (8, 32), (4, 16), (2, 8), (1, 4), (1, 2), (1, 1)]
+        );
+        assert_eq!(sizes(1, 1), vec![(1, 1)]);
+    }
+
+    #[test]
+    fn sprites_in_a_viewport_off_the_canvas_keep_their_projection() {
+        let texture = Texture::from_color(RED);
+        let sprite = sprite_at(&texture, 2.0, 0.0);
+        let mut camera = camera_at(0.0, 0.0);
+        let mut canvas = Canvas::headless(40, 40);
+
+        // Only the top left quarter of the viewport is on the canvas. The sprite is 10 pixels
+        // across centered on the viewport's middle, so a quarter of it lands in the corner
+        camera.set_viewport(Some(Viewport::new(20, 20, 40, 40)));
+        camera.render_sprites(&mut canvas, &open_map(), &[&sprite], &Lighting::default());
+
+        assert_eq!(pixel(&canvas, 36, 36), RED);
+        assert_eq!(pixel(&canvas, 39, 39), RED);
+        assert_eq!(pixel(&canvas, 34, 36), 0);
+        assert_eq!(pixel(&canvas, 36, 34), 0);
+    }
+
+    #[test]
+    fn sprites_in_a_viewport_below_the_canvas_draw_nothing() {
+        let texture = Texture::from_color(RED);
+        let sprite = sprite_at(&texture, 2.0, 0.0);
+        let mut camera = camera_at(0.0, 0.0);
+        let mut canvas = Canvas::headless(40, 40);
+
+        camera.set_viewport(Some(Viewport::new(0, 50, 40, 40)));
+        camera.render_sprites(&mut canvas, &open_map(), &[&sprite], &Lighting::default());
+
+        assert!(canvas.buffer.0.iter().flatten().all(|&c| c == 0));
+    }
+
+    #[test]
+    fn top_of_a_wall_below_the_eye_is_drawn() {
+        let (face, top) = (Texture::from_color(RED), Texture::from_color(GREEN));
+        let map = Map::new(
+            vec![vec![0; 5], vec![0, 0, 1, 0, 0], vec![0; 5]],
+            vec![
+                Tile::empty(),
+                Tile::wall(&face).with_height(0.25).with_top(&top),
+            ],
+        );
+        let camera = camera_at(1.5, 1.5);
+        let mut canvas = Canvas::headless(40, 40);
+
+        camera.main(&mut canvas, &map, &Lighting::default());
+
+        // Looking straight down the middle column, the face is half a unit away so it runs from
+        // row 30 to the bottom, and its top runs back to the far side of the cell at row 23
+        assert_eq!(pixel(&canvas, 20, 35), RED);
+        assert!((24..30).all(|y| pixel(&canvas, 20, y) == GREEN));
+        assert_eq!(pixel(&canvas, 20, 21), 0);
+    }
+
+    #[test]
+    fn row_on_the_horizon_is_still_drawn() {
+        let (floor, ceiling) = (Texture::from_color(RED), Texture::from_color(GREEN));
+        let map = Map::new(
+            vec![vec![0; 64]; 64],
+            vec![Tile::empty().with_floor(&floor).with_ceiling(&ceiling)],
+        );
+        let camera = camera_at(32.0, 32.0);
+
+        // An odd height puts the horizon on the center of row 20
+        let mut canvas = Canvas::headless(41, 41);
+        camera.draw_floor(&mut canvas, &map, None, &Lighting::default());
+
+        assert!((0..=20).all(|y| pixel(&canvas, 20, y) == GREEN));
+        assert!((21..41).all(|y| pixel(&canvas, 20, y) == RED));
+    }
+
+    // Writes each column's index within the viewport plus one into every pixel it's handed,
+    // so untouched pixels stay 0
+    fn fill_columns(canvas: &mut Canvas, viewport: Viewport, rows: usize) {
+        canvas.par_columns(viewport, |c, column, depth| {
+            assert_eq!(column.len(), rows);
+            assert_eq!(depth.len(), rows);
+            column.fill(c as u32 + 1);
+        });
+    }
+
+    #[test]
+    fn par_columns_covers_each_column_once_with_uneven_threads() {
+        for threads in [1, 3, 4, 16] {
+            let mut canvas = Canvas::headless(9, 5);
+            canvas.set_threads(threads);
+
+            fill_columns(&mut canvas, Viewport::new(1, 1, 7, 3), 3);
+
+            for x in 0..9 {
+                for y in 0..5 {
+                    let inside = (1..8).contains(&x) && (1..4).contains(&y);
+                    let expected = if inside { x as u32 } else { 0 };
+                    assert_eq!(
+                        pixel(&canvas, x, y),
+                        expected,
+                        "{threads} threads at {x}, {y}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn par_columns_skips_the_part_of_a_viewport_off_the_canvas() {
+        let mut canvas = Canvas::headless(12, 15);
+        canvas.set_threads(3);
+
+        fill_columns(&mut canvas, Viewport::new(3, 2, 11, 20), 13);
+
+        for x in 0..12 {
+            for y in 0..15 {
+                let expected = if x >= 3 && y >= 2 { x as u32 - 2 } else { 0 };
+                assert_eq!(pixel(&canvas, x, y), expected, "at {x}, {y}");
+            }
+        }
+    }
+
+    // Renders sprites for a camera at (1, 4) looking along +x on an open map. With a
+    // 40 pixel canvas the projection is 20 pixels per unit at a distance of 1 and the
+    // horizon is row 20, so a sprite on the floor f units ahead spans rows 20 - 10 / f to 20 + 10 / f
+    fn render_sprites_ahead(sprites: &[&Sprite]) -> Canvas {
+        let camera = camera_at(1.0, 4.0);
+        let mut canvas = Canvas::headless(40, 40);
+        camera.render_sprites(&mut canvas, &open_map(), sprites, &Lighting::default());
+        canvas
+    }
+
+    #[test]
+    fn nearer_sprite_is_drawn_over_farther_one_in_any_order() {
+        let (red, green) = (Texture::from_color(RED), Texture::from_color(GREEN));
+        let near = sprite_at(&red, 3.0, 4.0);
+        let far = sprite_at(&green, 5.0, 4.0);
+
+        for sprites in [[&near, &far], [&far, &near]] {
+            let canvas = render_sprites_ahead(&sprites);
+
+            assert_eq!(pixel(&canvas, 20, 20), RED);
+            assert_eq!(pixel(&canvas, 20, 16), RED);
+        }
+    }
+
+    #[test]
+    fn sprite_behind_a_wall_is_hidden() {
+        let (red, blue) = (Texture::from_color(RED), Texture::from_color(BLUE));
+        let map = Map::new(
+            vec![vec![0, 0, 0, 1, 0, 0, 0, 0]; 8],
+            vec![Tile::empty(), Tile::wall(&blue)],
+        );
+        let camera = camera_at(1.0, 4.0);
+        let behind = sprite_at(&red, 5.0, 4.0);
+        let in_front = sprite_at(&red, 2.0, 3.25);
+
+        let mut canvas = Canvas::headless(40, 40);
+        camera.main(&mut canvas, &map, &Lighting::default());
+        camera.render_sprites(
+            &mut canvas,
+            &map,
+            &[&behind, &in_front],
+            &Lighting::default(),
+        );
+
+        // The wall is 2 units away, so it covers rows 15 to 25 across the whole screen
+        assert_eq!(pixel(&canvas, 20, 20), BLUE);
+        assert_eq!(pixel(&canvas, 8, 20), RED);
+    }
+
+    #[test]
+    fn sprite_is_projected_from_camera_space() {
+        let red = Texture::from_color(RED);
+
+        // 2 ahead and 1 to the right puts the center on column 30, 10 pixels square
+        let sprite = sprite_at(&red, 3.0, 5.0);
+        let canvas = render_sprites_ahead(&[&sprite]);
+
+        assert_eq!(pixel(&canvas, 30, 20), RED);
+        assert_eq!(pixel(&canvas, 25, 15), RED);
+        assert_eq!(pixel(&canvas, 34, 24), RED);
+        assert_eq!(pixel(&canvas, 24, 20), 0);
+        assert_eq!(pixel(&canvas, 35, 20), 0);
+        assert_eq!(pixel(&canvas, 30, 14), 0);
+        assert_eq!(pixel(&canvas, 30, 25), 0);
+    }
+
+    #[test]
+    fn sprite_centered_off_screen_is_still_partly_drawn() {
+        let red = Texture::from_color(RED);
+
+        // Centered on column 42, so only its left 3 columns are on screen
+        let sprite = sprite_at(&red, 3.0, 6.2);
+        let canvas = render_sprites_ahead(&[&sprite]);
+
+        assert_eq!(pixel(&canvas, 38, 20), RED);
+        assert_eq!(pixel(&canvas, 39, 20), RED);
+        assert_eq!(pixel(&canvas, 36, 20), 0);
+    }
+
+    #[test]
+    fn sprite_closer_than_the_near_plane_is_drawn_at_it() {
+        let red = Texture::from_color(RED);
+
+        // At the near plane the projection is 100 pixels per unit, so this is 10 pixels
+        // square and centered on the middle of the screen
+        let mut sprite = sprite_at(&red, 1.1, 4.0);
+        sprite.scale(0.1);
+        sprite.elevation(0.45);
+        let canvas = render_sprites_ahead(&[&sprite]);
+
+        assert_eq!(pixel(&canvas, 20, 20), RED);
+        assert_eq!(pixel(&canvas, 16, 16), RED);
+        assert_eq!(pixel(&canvas, 13, 20), 0);
+        assert_eq!(pixel(&canvas, 20, 13), 0);
+    }
+
+    #[test]
+    fn sprite_behind_the_camera_is_dropped() {
+        let red = Texture::from_color(RED);
+        let sprite = sprite_at(&red, -1.0, 4.0);
+        let canvas = render_sprites_ahead(&[&sprite]);
+
+        assert!(canvas.buffer.0.iter().flatten().all(|&c| c == 0));
+    }
+
+    #[test]
+    fn elevation_lifts_a_sprite_off_the_floor() {
+        let red = Texture::from_color(RED);
+        let mut sprite = sprite_at(&red, 3.0, 4.0);
+        sprite.scale(0.5);
+
+        // On the floor 2 units away, half a unit tall is rows 20 to 25
+        let canvas = render_sprites_ahead(&[&sprite]);
+        assert_eq!(pixel(&canvas, 20, 22), RED);
+        assert_eq!(pixel(&canvas, 20, 19), 0);
+
+        // Hanging from the ceiling its bottom is a quarter unit above the eye, rows 12 to 17
+        sprite.elevation(0.75);
+        let canvas = render_sprites_ahead(&[&sprite]);
+        assert_eq!(pixel(&canvas, 20, 12), RED);
+        assert_eq!(pixel(&canvas, 20, 16), RED);
+        assert_eq!(pixel(&canvas, 20, 18), 0);
+        assert_eq!(pixel(&canvas, 20, 22), 0);
+    }
+
+    #[test]
+    fn pitch_is_clamped_however_it_is_set() {
+        let mut camera = camera_at(0.0, 0.0);
+
+        camera.set_pitch(2.0);
+        assert_eq!(camera.pitch(), MAX_PITCH);
+
+        camera.update_pitch(-3.0);
+        assert_eq!(camera.pitch(), -MAX_PITCH);
+
+        let options: cameraspec::CameraOptions =
+            cameraspec::CameraOptionsBuilder::new().pitch(0.9).into();
+        let camera: Camera = options.into();
+        assert_eq!(camera.pitch(), MAX_PITCH);
+    }
+
+    #[test]
+    fn pitch_slides_the_horizon() {
+        let mut camera = camera_at(0.0, 0.0);
+        assert_eq!(camera.horizon(100), 50.0);
+
+        // Looking up moves the horizon down the screen and the other way round
+        camera.set_pitch(0.2);
+        assert!((camera.horizon(100) - 70.0).abs() < 1e-4);
+        camera.set_pitch(-0.5);
+        assert_eq!(camera.horizon(100), 0.0);
+    }
+
+    #[test]
+    fn eye_adds_the_offset_and_stays_between_floor_and_ceiling() {
+        let mut camera = camera_at(0.0, 0.0);
+        assert_eq!(camera.eye(), DEFAULT_EYE_HEIGHT);
+
+        camera.set_eye_height(0.6);
+        camera.set_vertical_offset(-0.2);
+        assert!((camera.eye() - 0.4).abs() < 1e-4);
+
+        camera.set_vertical_offset(2.0);
+        assert_eq!(camera.eye(), 0.99);
+        camera.set_vertical_offset(-2.0);
+        assert_eq!(camera.eye(), 0.01);
+    }
+
+    #[test]
+    fn projection_fits_the_field_of_view_across_the_width() {
+        let mut camera = camera_at(0.0, 0.0);
+        assert!((camera.projection(40) - 20.0).abs() < 1e-4);
+
+        camera.set_fov(60.0);
+        let expected = 700.0 / (2.0 * 30.0_f32.to_radians().tan());
+        assert!((camera.projection(700) - expected).abs() < 1e-2);
+
+        // The edge columns look out along the edges of the field of view
+        assert!((camera.ray_angle(0, 700) + 30.0_f32.to_radians()).abs() < 1e-4);
+        assert!((camera.ray_angle(350, 700) - camera.view_angle).abs() < 1e-6);
+    }
+
+    #[test]
+    fn fov_is_set_in_degrees_and_clamped() {
+        let mut camera = camera_at(0.0, 0.0);
+
+        camera.set_fov(75.0);
+        assert!((camera.fov() - 75.0).abs() < 1e-4);
+
+        camera.set_fov(0.0);
+        assert!((camera.fov() - 1.0).abs() < 1e-4);
+        camera.set_fov(200.0);
+        assert!((camera.fov() - 179.0).abs() < 1e-4);
+
+        let options: cameraspec::CameraOptions = cameraspec::CameraOptionsBuilder::new()
+            .fov_degrees(90.0)
+            .into();
+        let camera: Camera = options.into();
+        assert!((camera.projection(40) - 20.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn headless_frame_round_trips_through_png() {
+        let mut canvas = Canvas::headless(6, 4);
+        assert!(canvas.is_headless());
+
+        canvas.par_columns(Viewport::new(0, 0, 6, 4), |c, column, _| {
+            for (y, pixel) in column.iter_mut().enumerate() {
+                *pixel = from_u8_rgb(c as u8 * 40, y as u8 * 60, 200);
+            }
+        });
+
+        let path = std::env::temp_dir().join(format!("badtracing-{}.png", std::process::id()));
+        canvas.save_png(&path).unwrap();
+        let image = image::open(&path).unwrap().to_rgb8();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(image.dimensions(), (6, 4));
+        for (x, y, p) in image.enumerate_pixels() {
+            assert_eq!(p.0, [x as u8 * 40, y as u8 * 60, 200], "at {x}, {y}");
+        }
+
+        // Saving doesn't touch the frame, updating clears it
+        assert_eq!(pixel(&canvas, 5, 3), from_u8_rgb(200, 180, 200));
+        canvas.update();
+        assert_eq!(pixel(&canvas, 5, 3), 0);
+    }
 }
@@ -1,7 +1,8 @@
 use std::error::Error;
 use std::fmt;
 
-use super::{Camera, Position};
+use super::lighting::Flashlight;
+use super::{blend, Camera, Position, Viewport};
 
 #[derive(Debug)]
 struct CameraBuildError {
@@ -20,10 +21,14 @@ impl fmt::Display for CameraBuildError {
 pub struct CameraOptions {
     position: Position,
     view_angle: f32,
-    focal_distance: f32,
-    viewport_size: f32,
-    ray_fineness: f32,
+    fov: f32, // Horizontal field of view in radians
     camera_fog: CameraFog,
+    viewport: Option<Viewport>,
+    eye_height: f32,
+    pitch: f32,
+    sky_fog: SkyFog,
+    flashlight: Option<Flashlight>,
+    side_shading: SideShading,
 }
 
 impl Default for CameraOptions {
@@ -31,49 +36,66 @@ impl Default for CameraOptions {
         Self {
             position: Position { x: 0.0, y: 0.0 },
             view_angle: 0.0,
-            focal_distance: 1.0,
-            viewport_size: 1.0,
-            ray_fineness: 100.0,
+            fov: super::DEFAULT_FOV_DEGREES.to_radians(),
             camera_fog: CameraFog::None,
+            viewport: None,
+            eye_height: super::DEFAULT_EYE_HEIGHT,
+            pitch: 0.0,
+            sky_fog: SkyFog::default(),
+            flashlight: None,
+            side_shading: SideShading::None,
         }
     }
 }
 
-impl Into<CameraOptionsBuilder> for CameraOptions {
-    fn into(self) -> CameraOptionsBuilder {
-        CameraOptionsBuilder {
-            position: self.position,
-            view_angle: self.view_angle,
-            focal_distance: self.focal_distance,
-            viewport_size: self.viewport_size,
-            ray_fineness: self.ray_fineness,
-            camera_fog: self.camera_fog,
+impl From<CameraOptions> for CameraOptionsBuilder {
+    fn from(options: CameraOptions) -> Self {
+        Self {
+            position: options.position,
+            view_angle: options.view_angle,
+            fov: options.fov,
+            camera_fog: options.camera_fog,
+            viewport: options.viewport,
+            eye_height: options.eye_height,
+            pitch: options.pitch,
+            sky_fog: options.sky_fog,
+            flashlight: options.flashlight,
+            side_shading: options.side_shading,
         }
     }
 }
 
-impl Into<CameraOptions> for CameraOptionsBuilder {
-    fn into(self) -> CameraOptions {
-        CameraOptions {
-            position: self.position,
-            view_angle: self.view_angle,
-            focal_distance: self.focal_distance,
-            viewport_size: self.viewport_size,
-            ray_fineness: self.ray_fineness,
-            camera_fog: self.camera_fog,
+impl From<CameraOptionsBuilder> for CameraOptions {
+    fn from(builder: CameraOptionsBuilder) -> Self {
+        Self {
+            position: builder.position,
+            view_angle: builder.view_angle,
+            fov: builder.fov,
+            camera_fog: builder.camera_fog,
+            viewport: builder.viewport,
+            eye_height: builder.eye_height,
+            pitch: builder.pitch,
+            sky_fog: builder.sky_fog,
+            flashlight: builder.flashlight,
+            side_shading: builder.side_shading,
         }
     }
 }
 
-impl Into<Camera> for CameraOptions {
-    fn into(self) -> Camera {
+impl From<CameraOptions> for Camera {
+    fn from(options: CameraOptions) -> Self {
         Camera {
-            position: self.position,
-            view_angle: self.view_angle,
-            focal_distance: self.focal_distance,
-            viewport_size: self.viewport_size,
-            ray_fineness: self.ray_fineness,
-            camera_fog: self.camera_fog,
+            position: options.position,
+            view_angle: options.view_angle,
+            fov: options.fov,
+            camera_fog: options.camera_fog,
+            viewport: options.viewport,
+            eye_height: options.eye_height,
+            eye_offset: 0.0,
+            pitch: options.pitch.clamp(-super::MAX_PITCH, super::MAX_PITCH),
+            sky_fog: options.sky_fog,
+            flashlight: options.flashlight,
+            side_shading: options.side_shading,
         }
     }
 }
@@ -81,10 +103,14 @@ impl Into<Camera> for CameraOptions {
 pub struct CameraOptionsBuilder {
     position: Position,
     view_angle: f32,
-    focal_distance: f32,
-    viewport_size: f32,
-    ray_fineness: f32,
+    fov: f32, // Horizontal field of view in radians
     camera_fog: CameraFog,
+    viewport: Option<Viewport>,
+    eye_height: f32,
+    pitch: f32,
+    sky_fog: SkyFog,
+    flashlight: Option<Flashlight>,
+    side_shading: SideShading,
 }
 
 impl CameraOptionsBuilder {
@@ -92,10 +118,14 @@ impl CameraOptionsBuilder {
         Self {
             position: Position { x: 0.0, y: 0.0 },
             view_angle: 0.0,
-            focal_distance: 1.0,
-            viewport_size: 1.0,
-            ray_fineness: 100.0,
+            fov: super::DEFAULT_FOV_DEGREES.to_radians(),
             camera_fog: CameraFog::None,
+            viewport: None,
+            eye_height: super::DEFAULT_EYE_HEIGHT,
+            pitch: 0.0,
+            sky_fog: SkyFog::default(),
+            flashlight: None,
+            side_shading: SideShading::None,
         }
     }
 
@@ -109,28 +139,281 @@ impl CameraOptionsBuilder {
         self
     }
 
-    pub fn focal_distance(mut self, focal_distance: f32) -> Self {
-        self.focal_distance = focal_distance;
+    /// Horizontal field of view. The vertical one follows from the canvas aspect ratio
+    pub fn fov_degrees(mut self, fov_degrees: f32) -> Self {
+        self.fov = super::clamp_fov(fov_degrees).to_radians();
         self
     }
 
-    pub fn viewport_size(mut self, viewport_size: f32) -> Self {
-        self.viewport_size = viewport_size;
+    pub fn camera_fog(mut self, camera_fog: CameraFog) -> Self {
+        self.camera_fog = camera_fog;
         self
     }
 
-    pub fn ray_fineness(mut self, ray_fineness: f32) -> Self {
-        self.ray_fineness = ray_fineness;
+    pub fn side_shading(mut self, side_shading: SideShading) -> Self {
+        self.side_shading = side_shading;
         self
     }
 
-    pub fn camera_fog(mut self, camera_fog: CameraFog) -> Self {
-        self.camera_fog = camera_fog;
+    pub fn sky_fog(mut self, sky_fog: SkyFog) -> Self {
+        self.sky_fog = sky_fog;
+        self
+    }
+
+    pub fn pitch(mut self, pitch: f32) -> Self {
+        self.pitch = pitch;
+        self
+    }
+
+    pub fn eye_height(mut self, eye_height: f32) -> Self {
+        self.eye_height = eye_height;
+        self
+    }
+
+    pub fn viewport(mut self, viewport: Viewport) -> Self {
+        self.viewport = Some(viewport);
+        self
+    }
+
+    pub fn flashlight(mut self, flashlight: Flashlight) -> Self {
+        self.flashlight = Some(flashlight);
         self
     }
 }
 
+/// Fog blends its color into everything by how far away it is.
+/// VisibleDistance is the old hard cutoff, nothing is fogged until fog_dist and then it's solid
 pub enum CameraFog {
     None,
-    VisibleDistance { fog_dist: f32, fog_color: u32 },
+    VisibleDistance {
+        fog_dist: f32,
+        fog_color: u32,
+    },
+    Linear {
+        start: f32,
+        end: f32,
+        fog_color: u32,
+    }, // Ramps from clear at start to solid at end, a hard step at start if end isn't past it
+    Exponential {
+        density: f32,
+        fog_color: u32,
+    },
+    ExponentialSquared {
+        density: f32,
+        fog_color: u32,
+    }, // Stays clearer up close, then thickens fast
+    Height {
+        density: f32,
+        falloff: f32,
+        fog_color: u32,
+    }, // Exponential, with density at the floor thinning by falloff per unit of height
+}
+
+/// Fog counts as solid once it covers this much, used to stop rays early
+const SOLID_FOG: f32 = 0.999;
+
+impl CameraFog {
+    /// How much fog covers something at this distance, 0.0 is clear and 1.0 is solid fog.
+    /// Height fog is taken at the floor, where it's thickest
+    pub fn amount(&self, distance: f32) -> f32 {
+        self.amount_at(distance, 0.0, 0.0)
+    }
+
+    /// Like amount, but for something height above the floor seen by a camera eye above the floor.
+    /// Only height fog cares, it sums its density along the ray between the two heights
+    pub fn amount_at(&self, distance: f32, eye: f32, height: f32) -> f32 {
+        match *self {
+            CameraFog::None => 0.0,
+            CameraFog::VisibleDistance { fog_dist, .. } => {
+                if distance > fog_dist {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            CameraFog::Linear { start, end, .. } => {
+                if end <= start {
+                    // No ramp at all, just a hard step at start
+                    if distance >= start {
+                        1.0
+                    } else {
+                        0.0
+                    }
+                } else {
+                    ((distance - start) / (end - start)).clamp(0.0, 1.0)
+                }
+            }
+            CameraFog::Exponential { density, .. } => 1.0 - (-density * distance).exp(),
+            CameraFog::ExponentialSquared { density, .. } => {
+                1.0 - (-(density * distance).powi(2)).exp()
+            }
+            CameraFog::Height {
+                density, falloff, ..
+            } => {
+                // Mean of density * e^(-falloff * z) as z goes in a straight line from eye to height
+                let rise = falloff * (height - eye);
+                let mean = if rise.abs() < 1e-4 {
+                    (-falloff * eye).exp()
+                } else {
+                    ((-falloff * eye).exp() - (-falloff * height).exp()) / rise
+                };
+                1.0 - (-density * mean * distance).exp()
+            }
+        }
+    }
+
+    pub fn color(&self) -> Option<u32> {
+        match *self {
+            CameraFog::None => None,
+            CameraFog::VisibleDistance { fog_color, .. }
+            | CameraFog::Linear { fog_color, .. }
+            | CameraFog::Exponential { fog_color, .. }
+            | CameraFog::ExponentialSquared { fog_color, .. }
+            | CameraFog::Height { fog_color, .. } => Some(fog_color),
+        }
+    }
+
+    /// Distance past which the fog is solid and nothing needs drawing, None if it never is.
+    /// Height fog depends on how high up things are, so it's never solid everywhere at once
+    pub fn solid_distance(&self) -> Option<f32> {
+        let cutoff = -(1.0 - SOLID_FOG).ln();
+        match *self {
+            CameraFog::None => None,
+            CameraFog::VisibleDistance { fog_dist, .. } => Some(fog_dist),
+            CameraFog::Linear { start, end, .. } => Some(end.max(start)),
+            CameraFog::Exponential { density, .. } => Some(cutoff / density),
+            CameraFog::ExponentialSquared { density, .. } => Some(cutoff.sqrt() / density),
+            CameraFog::Height { .. } => None,
+        }
+    }
+
+    /// Blends the fog color into a color seen at this distance
+    pub fn apply(&self, color: u32, distance: f32) -> u32 {
+        self.apply_at(color, distance, 0.0, 0.0)
+    }
+
+    /// Blends the fog color into a color seen at this distance and height, see amount_at
+    pub fn apply_at(&self, color: u32, distance: f32, eye: f32, height: f32) -> u32 {
+        match self.color() {
+            Some(fog_color) => {
+                let amount = self.amount_at(distance, eye, height);
+                blend(color, fog_color, (amount * 255.0) as u8)
+            }
+            None => color,
+        }
+    }
+}
+
+/// How fog covers the skybox, since the sky is infinitely far away and any fog would
+/// hide it completely. Fog is solid at the horizon and thins out to top_amount over the
+/// band, given as a fraction of the sky's height. Everything above the band stays at top_amount
+#[derive(Clone, Copy)]
+pub struct SkyFog {
+    pub band: f32,
+    pub top_amount: f32,
+}
+
+impl Default for SkyFog {
+    fn default() -> Self {
+        Self {
+            band: 0.5,
+            top_amount: 0.6,
+        }
+    }
+}
+
+impl SkyFog {
+    /// How much of the fog covers the sky at a height, 0.0 being the horizon and 1.0 the top
+    pub fn amount(&self, height: f32) -> f32 {
+        let t = (height / self.band).clamp(0.0, 1.0);
+        let t = t * t * (3.0 - 2.0 * t);
+        1.0 + (self.top_amount - 1.0) * t
+    }
+}
+
+/// Extra darkening for every wall face on one axis, the classic raycaster depth cue.
+/// The value is how much to take off each color channel
+#[derive(Clone, Copy)]
+pub enum SideShading {
+    None,
+    NorthSouth(u32),
+    EastWest(u32),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f32 = 1e-4;
+
+    fn height_fog(falloff: f32) -> CameraFog {
+        CameraFog::Height {
+            density: 0.3,
+            falloff,
+            fog_color: 0,
+        }
+    }
+
+    #[test]
+    fn linear_fog_ramps_between_start_and_end() {
+        let fog = CameraFog::Linear {
+            start: 2.0,
+            end: 6.0,
+            fog_color: 0,
+        };
+
+        assert_eq!(fog.amount(1.0), 0.0);
+        assert!((fog.amount(3.0) - 0.25).abs() < EPSILON);
+        assert_eq!(fog.amount(8.0), 1.0);
+        assert_eq!(fog.solid_distance(), Some(6.0));
+    }
+
+    #[test]
+    fn linear_fog_with_no_ramp_is_a_hard_step() {
+        for end in [4.0, 3.0] {
+            let fog = CameraFog::Linear {
+                start: 4.0,
+                end,
+                fog_color: 0,
+            };
+
+            assert_eq!(fog.amount(3.9), 0.0);
+            assert_eq!(fog.amount(4.0), 1.0);
+            assert_eq!(fog.amount(10.0), 1.0);
+            assert_eq!(fog.solid_distance(), Some(4.0));
+        }
+    }
+
+    #[test]
+    fn height_fog_without_falloff_is_exponential() {
+        let exponential = CameraFog::Exponential {
+            density: 0.3,
+            fog_color: 0,
+        };
+
+        for (distance, height) in [(1.0, 0.0), (4.0, 0.5), (9.0, 1.0)] {
+            let expected = exponential.amount(distance);
+            assert!((height_fog(0.0).amount_at(distance, 0.5, height) - expected).abs() < EPSILON);
+        }
+    }
+
+    #[test]
+    fn height_fog_thins_out_going_up() {
+        let fog = height_fog(3.0);
+        let floor = fog.amount_at(5.0, 0.5, 0.0);
+        let level = fog.amount_at(5.0, 0.5, 0.5);
+        let ceiling = fog.amount_at(5.0, 0.5, 1.0);
+
+        assert!(floor > level && level > ceiling);
+        assert!(fog.solid_distance().is_none());
+    }
+
+    #[test]
+    fn height_fog_is_continuous_at_eye_level() {
+        let fog = height_fog(2.0);
+        let level = fog.amount_at(5.0, 0.5, 0.5);
+        let near_level = fog.amount_at(5.0, 0.5, 0.5 + 1e-3);
+
+        assert!((level - near_level).abs() < 1e-3);
+    }
 }
@@ -0,0 +1,402 @@
+use std::time::Duration;
+
+use super::raycast::GridRay;
+use super::Position;
+use crate::map::Map;
+
+/// Walls closer to a lit point than this along the light's ray don't shadow it,
+/// so a wall doesn't shadow its own face
+const SHADOW_BIAS: f32 = 0.001;
+
+/// How much light reaches a point, per color channel. 1.0 shows a texture at its
+/// normal brightness, anything above that brightens it
+#[derive(Clone, Copy, Debug)]
+pub struct LightLevel {
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+}
+
+impl LightLevel {
+    pub fn gray(level: f32) -> Self {
+        Self {
+            r: level,
+            g: level,
+            b: level,
+        }
+    }
+
+    /// Light of a color, scaled by intensity
+    pub fn from_color(color: u32, intensity: f32) -> Self {
+        Self {
+            r: ((color >> 16) & 255) as f32 / 255.0 * intensity,
+            g: ((color >> 8) & 255) as f32 / 255.0 * intensity,
+            b: (color & 255) as f32 / 255.0 * intensity,
+        }
+    }
+
+    pub fn add(self, other: LightLevel) -> Self {
+        Self {
+            r: self.r + other.r,
+            g: self.g + other.g,
+            b: self.b + other.b,
+        }
+    }
+
+    /// Lights a color, each channel is clamped to 255
+    pub fn apply(&self, color: u32) -> u32 {
+        let channel = |shift: u32, level: f32| {
+            let c = ((color >> shift) & 255) as f32 * level;
+            (c.clamp(0.0, 255.0) as u32) << shift
+        };
+
+        channel(16, self.r) | channel(8, self.g) | channel(0, self.b)
+    }
+}
+
+/// A light placed in the world. Falls off smoothly to nothing at its radius,
+/// and anything solid between it and a point keeps it from lighting that point
+#[derive(Clone, Copy)]
+pub struct PointLight {
+    pub position: Position,
+    pub radius: f32,
+    pub intensity: f32,
+    pub color: u32,
+}
+
+impl PointLight {
+    pub fn new(position: Position, radius: f32, intensity: f32, color: u32) -> Self {
+        Self {
+            position,
+            radius,
+            intensity,
+            color,
+        }
+    }
+
+    /// Light this gives to a point in the world
+    pub fn level_at(&self, map: &Map, x: f32, y: f32) -> LightLevel {
+        let (dx, dy) = (x - self.position.x, y - self.position.y);
+        let distance_sq = dx * dx + dy * dy;
+
+        if distance_sq >= self.radius * self.radius || !self.reaches(map, x, y) {
+            return LightLevel::gray(0.0);
+        }
+
+        let falloff = 1.0 - distance_sq.sqrt() / self.radius;
+        LightLevel::from_color(self.color, self.intensity * falloff * falloff)
+    }
+
+    /// Whether the light can see a point, walking the grid between them for a solid tile.
+    /// The point's own cell never counts, that's the door slab or wall being lit.
+    /// Doors only let light through once they're fully open
+    fn reaches(&self, map: &Map, x: f32, y: f32) -> bool {
+        let (dx, dy) = (x - self.position.x, y - self.position.y);
+        let distance = (dx * dx + dy * dy).sqrt();
+        let angle = dy.atan2(dx);
+        let own_cell = (x.floor() as usize, y.floor() as usize);
+
+        GridRay::new(self.position, angle, angle, map.width(), map.height())
+            .take_while(|hit| hit.distance < distance - SHADOW_BIAS)
+            .filter(|hit| (hit.cell_x, hit.cell_y) != own_cell)
+            .all(|hit| {
+                let blocks = map.tile(hit.cell_x, hit.cell_y).is_some_and(|tile| {
+                    tile.is_solid()
+                        && map
+                            .door(hit.cell_x, hit.cell_y)
+                            .is_none_or(|door| door.open < 1.0)
+                });
+                !blocks
+            })
+    }
+}
+
+/// A cone of light coming from the camera along its view direction.
+/// Bright in the middle of the cone, fades out toward the edge and with distance
+#[derive(Clone, Copy)]
+pub struct Flashlight {
+    pub on: bool,
+    pub cone_angle: f32, // Full width of the cone in radians
+    pub range: f32,
+    pub intensity: f32,
+    pub color: u32,
+    pub drain_rate: f32, // Battery used per second while on, a full battery is 1.0
+    battery: f32,
+}
+
+impl Flashlight {
+    pub fn new(cone_angle: f32, range: f32, intensity: f32, color: u32) -> Self {
+        Self {
+            on: true,
+            cone_angle,
+            range,
+            intensity,
+            color,
+            drain_rate: 0.0,
+            battery: 1.0,
+        }
+    }
+
+    pub fn with_drain_rate(mut self, drain_rate: f32) -> Self {
+        self.drain_rate = drain_rate;
+        self
+    }
+
+    pub fn toggle(&mut self) {
+        self.on = !self.on;
+    }
+
+    pub fn battery(&self) -> f32 {
+        self.battery
+    }
+
+    pub fn recharge(&mut self, amount: f32) {
+        self.battery = (self.battery + amount).min(1.0);
+    }
+
+    /// Takes battery away directly, for anything that isn't just time passing.
+    /// The flashlight turns off once the battery is empty
+    pub fn drain(&mut self, amount: f32) {
+        self.battery = (self.battery - amount).max(0.0);
+        if self.battery == 0.0 {
+            self.on = false;
+        }
+    }
+
+    /// Drains the battery by drain_rate while the light is on, call once per frame
+    pub fn update(&mut self, dt: Duration) {
+        if self.on {
+            self.drain(self.drain_rate * dt.as_secs_f32());
+        }
+    }
+
+    /// Light this gives to a point in the world when held at origin, pointing along direction.
+    /// Dims once the battery gets low. A cone with no width gives no light
+    pub fn level_at(&self, origin: Position, direction: f32, x: f32, y: f32) -> LightLevel {
+        let (dx, dy) = (x - origin.x, y - origin.y);
+        let distance = (dx * dx + dy * dy).sqrt();
+
+        if !self.on || distance >= self.range || self.cone_angle <= 0.0 {
+            return LightLevel::gray(0.0);
+        }
+
+        // Points basically on top of the camera count as dead center
+        let off_center = if distance < 0.001 {
+            0.0
+        } else {
+            let cos = (dx * direction.cos() + dy * direction.sin()) / distance;
+            cos.clamp(-1.0, 1.0).acos()
+        };
+
+        // Full strength for the inner 60% of the cone, then a smooth fade to the edge
+        let half_angle = self.cone_angle / 2.0;
+        let edge = ((half_angle - off_center) / (half_angle * 0.4)).clamp(0.0, 1.0);
+        let angle_falloff = edge * edge * (3.0 - 2.0 * edge);
+
+        let distance_falloff = 1.0 - distance / self.range;
+        let battery_falloff = (self.battery * 5.0).min(1.0);
+
+        LightLevel::from_color(
+            self.color,
+            self.intensity * angle_falloff * distance_falloff * distance_falloff * battery_falloff,
+        )
+    }
+}
+
+/// Every light in a scene. Walls, floors and sprites all get lit through this
+pub struct Lighting {
+    pub ambient: LightLevel, // Light everything gets no matter where it is
+    lights: Vec<PointLight>,
+}
+
+impl Default for Lighting {
+    /// Fully lit with no lights, everything shows at its texture's brightness
+    fn default() -> Self {
+        Self::new(1.0)
+    }
+}
+
+impl Lighting {
+    pub fn new(ambient: f32) -> Self {
+        Self {
+            ambient: LightLevel::gray(ambient),
+            lights: Vec::new(),
+        }
+    }
+
+    /// Returns an index that can be used to move or change the light later
+    pub fn add_light(&mut self, light: PointLight) -> usize {
+        self.lights.push(light);
+        self.lights.len() - 1
+    }
+
+    pub fn light_mut(&mut self, index: usize) -> Option<&mut PointLight> {
+        self.lights.get_mut(index)
+    }
+
+    pub fn lights(&self) -> &[PointLight] {
+        &self.lights
+    }
+
+    /// Total light at a point in the world, lights on the far side of a wall don't count
+    pub fn level_at(&self, map: &Map, x: f32, y: f32) -> LightLevel {
+        self.lights.iter().fold(self.ambient, |level, light| {
+            level.add(light.level_at(map, x, y))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::{DoorAxis, Tile};
+    use crate::rendering::Texture;
+
+    const EPSILON: f32 = 1e-4;
+
+    // Two open rooms split by a wall along x = 2, with a door in it at (2, 2)
+    fn two_rooms(texture: &Texture) -> Map<'_> {
+        let row = |middle| vec![0, 0, middle, 0, 0];
+        Map::new(
+            vec![row(1), row(1), row(2), row(1), row(1)],
+            vec![
+                Tile::empty(),
+                Tile::wall(texture),
+                Tile::door(texture, DoorAxis::Y),
+            ],
+        )
+    }
+
+    fn white_light(x: f32, y: f32) -> PointLight {
+        PointLight::new(Position { x, y }, 4.0, 1.0, 0xFFFFFF)
+    }
+
+    #[test]
+    fn point_light_falls_off_to_nothing_at_its_radius() {
+        let texture = Texture::from_color(0);
+        let map = two_rooms(&texture);
+        let light = white_light(0.5, 0.5);
+
+        assert!((light.level_at(&map, 0.5, 0.5).r - 1.0).abs() < EPSILON);
+        assert!((light.level_at(&map, 0.5, 2.5).r - 0.25).abs() < EPSILON);
+        assert_eq!(light.level_at(&map, 0.5, 4.5).r, 0.0);
+    }
+
+    #[test]
+    fn wall_between_light_and_point_blocks_it() {
+        let texture = Texture::from_color(0);
+        let map = two_rooms(&texture);
+        let light = white_light(1.5, 0.5);
+
+        assert!(light.level_at(&map, 1.5, 1.5).r > 0.0);
+        assert_eq!(light.level_at(&map, 3.5, 0.5).r, 0.0);
+        assert_eq!(light.level_at(&map, 3.5, 1.5).r, 0.0);
+    }
+
+    #[test]
+    fn wall_face_is_lit_from_its_own_side() {
+        let texture = Texture::from_color(0);
+        let map = two_rooms(&texture);
+
+        assert!(white_light(1.0, 0.5).level_at(&map, 2.0, 1.2).r > 0.0);
+        assert_eq!(white_light(3.5, 0.5).level_at(&map, 2.0, 1.2).r, 0.0);
+    }
+
+    #[test]
+    fn door_slab_is_lit_from_its_own_side() {
+        let texture = Texture::from_color(0);
+        let map = two_rooms(&texture);
+
+        assert!(white_light(1.5, 2.5).level_at(&map, 2.5, 2.5).r > 0.0);
+    }
+
+    #[test]
+    fn light_passes_a_door_once_it_is_fully_open() {
+        let texture = Texture::from_color(0);
+        let mut map = two_rooms(&texture);
+        let light = white_light(1.5, 2.5);
+
+        assert_eq!(light.level_at(&map, 3.5, 2.5).r, 0.0);
+
+        map.toggle_door(2, 2);
+        map.update_doors(Duration::from_secs(1), &[]);
+        assert!(light.level_at(&map, 3.5, 2.5).r > 0.0);
+    }
+
+    #[test]
+    fn lighting_adds_ambient_and_only_unblocked_lights() {
+        let texture = Texture::from_color(0);
+        let map = two_rooms(&texture);
+        let mut lighting = Lighting::new(0.2);
+        lighting.add_light(white_light(0.5, 0.5));
+        lighting.add_light(white_light(4.5, 0.5));
+
+        let level = lighting.level_at(&map, 0.5, 0.5);
+        assert!((level.r - 1.2).abs() < EPSILON);
+    }
+
+    fn flashlight() -> Flashlight {
+        Flashlight::new(1.0, 4.0, 1.0, 0xFFFFFF)
+    }
+
+    const ORIGIN: Position = Position { x: 0.0, y: 0.0 };
+
+    #[test]
+    fn flashlight_lights_inside_its_cone_only() {
+        let light = flashlight();
+
+        assert!((light.level_at(ORIGIN, 0.0, 2.0, 0.0).r - 0.25).abs() < EPSILON);
+        assert!(light.level_at(ORIGIN, 0.0, 2.0, 0.5).r > 0.0);
+        assert_eq!(light.level_at(ORIGIN, 0.0, 2.0, 2.0).r, 0.0);
+        assert_eq!(light.level_at(ORIGIN, 0.0, -2.0, 0.0).r, 0.0);
+        assert_eq!(light.level_at(ORIGIN, 0.0, 5.0, 0.0).r, 0.0);
+    }
+
+    #[test]
+    fn flashlight_with_no_cone_gives_no_light() {
+        let mut light = flashlight();
+        light.cone_angle = 0.0;
+
+        assert_eq!(light.level_at(ORIGIN, 0.0, 2.0, 0.0).r, 0.0);
+        assert_eq!(light.level_at(ORIGIN, 0.0, 0.0, 0.0).r, 0.0);
+    }
+
+    #[test]
+    fn flashlight_turns_off_when_the_battery_runs_out() {
+        let mut light = flashlight().with_drain_rate(0.5);
+
+        light.update(Duration::from_secs(1));
+        assert!((light.battery() - 0.5).abs() < EPSILON);
+        assert!(light.on);
+
+        light.update(Duration::from_secs(2));
+        assert_eq!(light.battery(), 0.0);
+        assert!(!light.on);
+        assert_eq!(light.level_at(ORIGIN, 0.0, 2.0, 0.0).r, 0.0);
+
+        // Off, so nothing more drains
+        light.recharge(0.5);
+        light.update(Duration::from_secs(1));
+        assert_eq!(light.battery(), 0.5);
+    }
+
+    #[test]
+    fn flashlight_dims_on_a_low_battery() {
+        let mut light = flashlight();
+        light.drain(0.9);
+
+        assert!((light.level_at(ORIGIN, 0.0, 2.0, 0.0).r - 0.125).abs() < EPSILON);
+    }
+
+    #[test]
+    fn applying_light_clamps_each_channel() {
+        let bright = LightLevel {
+            r: 3.0,
+            g: 0.5,
+            b: 0.0,
+        };
+
+        assert_eq!(bright.apply(0x80_80_80), 0xFF_40_00);
+        assert_eq!(LightLevel::gray(1.0).apply(0x12_34_56), 0x12_34_56);
+    }
+}
@@ -0,0 +1,409 @@
+use super::Position;
+use crate::map::DoorAxis;
+
+/// The face of a map cell that a ray ran into.
+/// North is the face toward -y (the row above it in the map), West is toward -x.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WallSide {
+    North,
+    South,
+    East,
+    West,
+}
+
+/// A single cell boundary crossing reported by a `GridRay`
+#[derive(Clone, Copy)]
+pub struct RayHit {
+    pub cell_x: usize,
+    pub cell_y: usize,
+    pub point: Position, // Exact point the ray entered the cell at
+    pub side: WallSide,
+    pub distance: f32,      // Distance along the ray
+    pub perp_distance: f32, // Distance along the camera's view direction, no fisheye
+    pub u: f32,             // How far along the face the ray hit, [0, 1)
+    perp_scale: f32,
+}
+
+impl RayHit {
+    /// Given a hit on a door cell, finds where the ray meets the door's slab on the cell's midline.
+    /// None if the ray leaves the cell first or passes through the part the door has opened.
+    /// The slab slides toward +x (or +y) as it opens, u moves with it so the texture slides too
+    pub fn through_door(
+        &self,
+        dir_x: f32,
+        dir_y: f32,
+        axis: DoorAxis,
+        open: f32,
+    ) -> Option<RayHit> {
+        let (cell_x, cell_y) = (self.cell_x as f32, self.cell_y as f32);
+
+        // t is the extra distance along the ray to reach the midline
+        let (t, side) = match axis {
+            DoorAxis::X => (
+                (cell_y + 0.5 - self.point.y) / dir_y,
+                if dir_y > 0.0 {
+                    WallSide::North
+                } else {
+                    WallSide::South
+                },
+            ),
+            DoorAxis::Y => (
+                (cell_x + 0.5 - self.point.x) / dir_x,
+                if dir_x > 0.0 {
+                    WallSide::West
+                } else {
+                    WallSide::East
+                },
+            ),
+        };
+
+        // Also catches the ray running parallel to the slab, t is inf or NaN there
+        if !(t >= 0.0 && t.is_finite()) {
+            return None;
+        }
+
+        let point = Position {
+            x: self.point.x + dir_x * t,
+            y: self.point.y + dir_y * t,
+        };
+
+        let along = match axis {
+            DoorAxis::X => point.x - cell_x,
+            DoorAxis::Y => point.y - cell_y,
+        };
+
+        if !(0.0..1.0).contains(&along) || along < open {
+            return None;
+        }
+
+        let distance = self.distance + t;
+        Some(RayHit {
+            point,
+            side,
+            distance,
+            perp_distance: distance * self.perp_scale,
+            u: along - open,
+            ..*self
+        })
+    }
+}
+
+/// DDA traversal of the map grid. Every cell the ray crosses is yielded exactly once,
+/// in order, starting with the first cell after the one the ray starts in.
+/// Stops once the ray leaves the map.
+pub struct GridRay {
+    origin: Position,
+    dir_x: f32,
+    dir_y: f32,
+    perp_scale: f32, // cos of the angle between the ray and the view direction
+    map_x: isize,
+    map_y: isize,
+    step_x: isize,
+    step_y: isize,
+    side_dist_x: f32,
+    side_dist_y: f32,
+    delta_x: f32,
+    delta_y: f32,
+    width: isize,
+    height: isize,
+}
+
+impl GridRay {
+    pub fn new(
+        origin: Position,
+        ray_angle: f32,
+        view_angle: f32,
+        width: usize,
+        height: usize,
+    ) -> Self {
+        let (dir_x, dir_y) = (ray_angle.cos(), ray_angle.sin());
+        let (map_x, map_y) = (origin.x.floor(), origin.y.floor());
+
+        // Distance along the ray between two x (or y) grid lines
+        let delta_x = if dir_x == 0.0 {
+            f32::INFINITY
+        } else {
+            (1.0 / dir_x).abs()
+        };
+        let delta_y = if dir_y == 0.0 {
+            f32::INFINITY
+        } else {
+            (1.0 / dir_y).abs()
+        };
+
+        let (step_x, side_dist_x) = if dir_x < 0.0 {
+            (-1, (origin.x - map_x) * delta_x)
+        } else {
+            (1, (map_x + 1.0 - origin.x) * delta_x)
+        };
+
+        let (step_y, side_dist_y) = if dir_y < 0.0 {
+            (-1, (origin.y - map_y) * delta_y)
+        } else {
+            (1, (map_y + 1.0 - origin.y) * delta_y)
+        };
+
+        Self {
+            origin,
+            dir_x,
+            dir_y,
+            perp_scale: (ray_angle - view_angle).cos(),
+            map_x: map_x as isize,
+            map_y: map_y as isize,
+            step_x,
+            step_y,
+            side_dist_x,
+            side_dist_y,
+            delta_x,
+            delta_y,
+            width: width as isize,
+            height: height as isize,
+        }
+    }
+
+    /// The cell the ray starts in, as if it was hit right at the origin. Iterating never
+    /// reports this cell, it's here for things like doors that sit inside their cell.
+    /// None if the origin is off the map
+    pub fn start(&self) -> Option<RayHit> {
+        if self.map_x < 0 || self.map_x >= self.width || self.map_y < 0 || self.map_y >= self.height
+        {
+            return None;
+        }
+
+        Some(RayHit {
+            cell_x: self.map_x as usize,
+            cell_y: self.map_y as usize,
+            point: self.origin,
+            side: if self.step_x > 0 {
+                WallSide::West
+            } else {
+                WallSide::East
+            },
+            distance: 0.0,
+            perp_distance: 0.0,
+            u: self.origin.y - self.origin.y.floor(),
+            perp_scale: self.perp_scale,
+        })
+    }
+}
+
+impl Iterator for GridRay {
+    type Item = RayHit;
+
+    fn next(&mut self) -> Option<RayHit> {
+        let (distance, side) = if self.side_dist_x < self.side_dist_y {
+            let distance = self.side_dist_x;
+            self.side_dist_x += self.delta_x;
+            self.map_x += self.step_x;
+            (
+                distance,
+                if self.step_x > 0 {
+                    WallSide::West
+                } else {
+                    WallSide::East
+                },
+            )
+        } else {
+            let distance = self.side_dist_y;
+            self.side_dist_y += self.delta_y;
+            self.map_y += self.step_y;
+            (
+                distance,
+                if self.step_y > 0 {
+                    WallSide::North
+                } else {
+                    WallSide::South
+                },
+            )
+        };
+
+        if self.map_x < 0 || self.map_x >= self.width || self.map_y < 0 || self.map_y >= self.height
+        {
+            return None;
+        }
+
+        let point = Position {
+            x: self.origin.x + self.dir_x * distance,
+            y: self.origin.y + self.dir_y * distance,
+        };
+
+        // u runs along whichever axis the face lies on
+        let u = match side {
+            WallSide::East | WallSide::West => point.y - point.y.floor(),
+            WallSide::North | WallSide::South => point.x - point.x.floor(),
+        };
+
+        Some(RayHit {
+            cell_x: self.map_x as usize,
+            cell_y: self.map_y as usize,
+            point,
+            side,
+            distance,
+            perp_distance: distance * self.perp_scale,
+            u,
+            perp_scale: self.perp_scale,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f32 = 1e-4;
+
+    fn ray(x: f32, y: f32, angle: f32) -> GridRay {
+        GridRay::new(Position { x, y }, angle, angle, 5, 5)
+    }
+
+    // First crossing of a ray heading +x from inside the cell west of (2, 1)
+    fn hit_from_west(y: f32) -> RayHit {
+        ray(1.5, y, 0.0).next().unwrap()
+    }
+
+    fn cells(ray: GridRay) -> Vec<(usize, usize)> {
+        ray.map(|hit| (hit.cell_x, hit.cell_y)).collect()
+    }
+
+    #[test]
+    fn axis_aligned_rays_visit_each_cell_in_a_line() {
+        assert_eq!(cells(ray(1.5, 1.5, 0.0)), vec![(2, 1), (3, 1), (4, 1)]);
+        assert_eq!(
+            cells(ray(1.5, 1.5, std::f32::consts::FRAC_PI_2)),
+            vec![(1, 2), (1, 3), (1, 4)]
+        );
+        assert_eq!(
+            cells(ray(3.5, 2.5, std::f32::consts::PI)),
+            vec![(2, 2), (1, 2), (0, 2)]
+        );
+    }
+
+    #[test]
+    fn axis_aligned_hits_report_side_and_distance() {
+        let hits: Vec<RayHit> = ray(1.5, 1.25, 0.0).collect();
+
+        for (i, hit) in hits.iter().enumerate() {
+            assert_eq!(hit.side, WallSide::West);
+            assert!((hit.distance - (0.5 + i as f32)).abs() < EPSILON);
+            assert!((hit.perp_distance - hit.distance).abs() < EPSILON);
+            assert!((hit.u - 0.25).abs() < EPSILON);
+        }
+
+        let hit = ray(1.5, 3.5, -std::f32::consts::FRAC_PI_2).next().unwrap();
+        assert_eq!((hit.cell_x, hit.cell_y), (1, 2));
+        assert_eq!(hit.side, WallSide::South);
+
+        let hit = ray(1.5, 1.5, std::f32::consts::FRAC_PI_2).next().unwrap();
+        assert_eq!(hit.side, WallSide::North);
+
+        let hit = ray(3.5, 1.5, std::f32::consts::PI).next().unwrap();
+        assert_eq!(hit.side, WallSide::East);
+    }
+
+    #[test]
+    fn diagonal_ray_steps_to_a_neighbouring_cell_every_time() {
+        let visited = cells(ray(0.3, 0.6, 0.7));
+        assert!(!visited.is_empty());
+
+        let mut last: (usize, usize) = (0, 0);
+        for &cell in &visited {
+            let step = last.0.abs_diff(cell.0) + last.1.abs_diff(cell.1);
+            assert_eq!(step, 1, "jumped from {last:?} to {cell:?}");
+            last = cell;
+        }
+
+        let mut unique = visited.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), visited.len());
+    }
+
+    #[test]
+    fn diagonal_hits_land_on_grid_lines() {
+        for hit in ray(0.3, 0.6, 0.7) {
+            let on_line = match hit.side {
+                WallSide::West | WallSide::East => hit.point.x,
+                WallSide::North | WallSide::South => hit.point.y,
+            };
+            assert!((on_line - on_line.round()).abs() < EPSILON);
+
+            let (dx, dy) = (hit.point.x - 0.3, hit.point.y - 0.6);
+            assert!(((dx * dx + dy * dy).sqrt() - hit.distance).abs() < EPSILON);
+        }
+    }
+
+    #[test]
+    fn origin_exactly_on_a_grid_line() {
+        // Heading away from the line, the next line is a whole cell out
+        let hit = ray(2.0, 1.5, 0.0).next().unwrap();
+        assert_eq!((hit.cell_x, hit.cell_y), (3, 1));
+        assert!((hit.distance - 1.0).abs() < EPSILON);
+
+        // Heading back across it, the line is crossed straight away
+        let hit = ray(2.0, 1.5, std::f32::consts::PI).next().unwrap();
+        assert_eq!((hit.cell_x, hit.cell_y), (1, 1));
+        assert_eq!(hit.side, WallSide::East);
+        assert!(hit.distance.abs() < EPSILON);
+    }
+
+    #[test]
+    fn perp_distance_removes_fisheye() {
+        let (view, offset) = (0.0, 0.3);
+        let hit = GridRay::new(Position { x: 1.5, y: 1.5 }, view + offset, view, 5, 5)
+            .next()
+            .unwrap();
+
+        assert!((hit.perp_distance - hit.distance * offset.cos()).abs() < EPSILON);
+        assert!((hit.perp_distance - 0.5).abs() < EPSILON);
+    }
+
+    #[test]
+    fn ray_stops_at_the_map_edge() {
+        assert!(ray(4.5, 4.5, 0.0).next().is_none());
+        assert_eq!(cells(ray(0.5, 0.5, std::f32::consts::PI)), vec![]);
+    }
+
+    #[test]
+    fn closed_door_is_hit_on_the_midline() {
+        let hit = hit_from_west(1.25)
+            .through_door(1.0, 0.0, DoorAxis::Y, 0.0)
+            .unwrap();
+
+        assert_eq!((hit.cell_x, hit.cell_y), (2, 1));
+        assert_eq!(hit.side, WallSide::West);
+        assert!((hit.point.x - 2.5).abs() < EPSILON);
+        assert!((hit.distance - 1.0).abs() < EPSILON);
+        assert!((hit.u - 0.25).abs() < EPSILON);
+    }
+
+    #[test]
+    fn ray_through_open_gap_misses_the_door() {
+        let hit = hit_from_west(1.25);
+        assert!(hit.through_door(1.0, 0.0, DoorAxis::Y, 0.5).is_none());
+
+        // The slab has slid half a cell, so its texture has too
+        let hit = hit_from_west(1.75)
+            .through_door(1.0, 0.0, DoorAxis::Y, 0.5)
+            .unwrap();
+        assert!((hit.u - 0.25).abs() < EPSILON);
+    }
+
+    #[test]
+    fn ray_parallel_to_door_misses_it() {
+        let hit = hit_from_west(1.25);
+        assert!(hit.through_door(1.0, 0.0, DoorAxis::X, 0.0).is_none());
+    }
+
+    #[test]
+    fn start_reports_the_cell_the_ray_begins_in() {
+        let start = ray(2.3, 1.5, 0.0).start().unwrap();
+        assert_eq!((start.cell_x, start.cell_y), (2, 1));
+        assert_eq!(start.distance, 0.0);
+
+        // Standing in a door cell with the slab just ahead
+        let hit = start.through_door(1.0, 0.0, DoorAxis::Y, 0.0).unwrap();
+        assert!((hit.distance - 0.2).abs() < EPSILON);
+
+        assert!(ray(-1.0, 1.5, 0.0).start().is_none());
+    }
+}